@@ -186,11 +186,15 @@ fn main() {
         .unwrap();
 
     match matches.opt_str("execute") {
-        Some(cmd) => {
+        Some(line) => {
+            // Same as in the shell, eg "-e 'inc web'"
+            let mut chunks = line.split_whitespace();
+            let cmd = chunks.next().unwrap_or("");
+            let args = chunks.collect();
             match send_msg(&mut BufReader::new(&ctrl_stream),
                            &mut BufWriter::new(&ctrl_stream),
-                           &cmd,
-                           vec![]) {
+                           cmd,
+                           args) {
                 Ok(reply) => println!("{}", reply),
                 Err(e) => println!("Communications error: {}", e),
             }
@@ -0,0 +1,757 @@
+//  einhyrningsins: graceful restarts for socket-based daemons
+//  Copyright (C) 2016  Bryan Newbold <bnewbold@robocracy.org>
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU General Public License as published by
+//  the Free Software Foundation, either version 3 of the License, or
+//  (at your option) any later version.
+//
+//  This program is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//  GNU General Public License for more details.
+//
+//  You should have received a copy of the GNU General Public License
+//  along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+// The shared sockets (--bind): parsing the specs, binding them, and cleaning up after them. A
+// spec is an address followed by comma-separated options, eg "127.0.0.1:8080,r,n,backlog=1024",
+// "udp:0.0.0.0:8125,rcvbuf=1048576" or "unix:/run/app.sock,mode=0660,group=www-data".
+
+use std::env;
+use std::ffi::{CString, OsString};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::mem;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use libc;
+use nix;
+use nix::sys::socket::{AddressFamily, InetAddr, SockAddr, SockType};
+use slog;
+
+use config::PoolConfig;
+use pidfd;
+use procfs;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Addr {
+    Tcp(SocketAddr),
+    Udp(SocketAddr),
+    // A socket file
+    Unix(PathBuf),
+    // A name in Linux's abstract namespace ("unix:@NAME")
+    Abstract(String),
+}
+
+impl Addr {
+    pub fn is_tcp(&self) -> bool {
+        match *self {
+            Addr::Tcp(_) => true,
+            _ => false,
+        }
+    }
+
+    // For EINHORN_FD_TYPE_<n>
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            Addr::Tcp(_) => "tcp",
+            Addr::Udp(_) => "udp",
+            Addr::Unix(_) | Addr::Abstract(_) => "unix",
+        }
+    }
+}
+
+impl fmt::Display for Addr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Addr::Tcp(ref sa) => write!(f, "{}", sa),
+            Addr::Udp(ref sa) => write!(f, "udp:{}", sa),
+            Addr::Unix(ref path) => write!(f, "unix:{}", path.display()),
+            Addr::Abstract(ref name) => write!(f, "unix:@{}", name),
+        }
+    }
+}
+
+pub struct Spec {
+    pub addr: Addr,
+    // O_NONBLOCK
+    nonblock: bool,
+    // For listen(2)
+    backlog: libc::c_int,
+    // Socket options as (name, level, option, value), set before binding
+    opts: Vec<(&'static str, libc::c_int, libc::c_int, libc::c_int)>,
+    // For socket files
+    mode: Option<u32>,
+    owner: Option<libc::uid_t>,
+    group: Option<libc::gid_t>,
+}
+
+// Not in our version of libc
+const IP_FREEBIND: libc::c_int = 15;
+
+// Which kinds of socket an option applies to
+const TCP: u8 = 1;
+const UDP: u8 = 2;
+const UNIX: u8 = 4;
+
+// The socket options a spec can set: name, level, option, whether it takes a number (instead of
+// being a flag), and the kinds of socket it applies to
+const SOCK_OPTS: &'static [(&'static str, libc::c_int, libc::c_int, bool, u8)] =
+    &[("reuseaddr", libc::SOL_SOCKET, libc::SO_REUSEADDR, false, TCP | UDP),
+      ("reuseport", libc::SOL_SOCKET, libc::SO_REUSEPORT, false, TCP | UDP),
+      ("v6only", libc::IPPROTO_IPV6, libc::IPV6_V6ONLY, false, TCP | UDP),
+      ("freebind", libc::IPPROTO_IP, IP_FREEBIND, false, TCP | UDP),
+      ("nodelay", libc::IPPROTO_TCP, libc::TCP_NODELAY, false, TCP),
+      ("keepalive", libc::SOL_SOCKET, libc::SO_KEEPALIVE, false, TCP),
+      ("defer_accept", libc::IPPROTO_TCP, libc::TCP_DEFER_ACCEPT, true, TCP),
+      ("fastopen", libc::IPPROTO_TCP, libc::TCP_FASTOPEN, true, TCP),
+      ("sndbuf", libc::SOL_SOCKET, libc::SO_SNDBUF, true, TCP | UDP | UNIX),
+      ("rcvbuf", libc::SOL_SOCKET, libc::SO_RCVBUF, true, TCP | UDP | UNIX)];
+
+// Parses the pool's --bind specs (resolving host names, users and groups), without binding
+// anything
+pub fn resolve(cfg: &PoolConfig) -> Result<Vec<Spec>, String> {
+    let mut specs = vec![];
+    for b in &cfg.bind_slugs {
+        let mut chunks = b.split(',');
+        let addr_str = chunks.next().unwrap(); // safe
+        let addr = if addr_str.starts_with("unix:") {
+            match &addr_str[5..] {
+                "" | "@" => return Err(format!("Missing unix socket path in '{}'", b)),
+                name if name.starts_with('@') => Addr::Abstract(name[1..].to_string()),
+                path => Addr::Unix(PathBuf::from(path)),
+            }
+        } else if addr_str.starts_with("udp:") {
+            Addr::Udp(try!(resolve_inet(&addr_str[4..], cfg)))
+        } else {
+            Addr::Tcp(try!(resolve_inet(addr_str, cfg)))
+        };
+        let kind = match addr {
+            Addr::Tcp(_) => TCP,
+            Addr::Udp(_) => UDP,
+            Addr::Unix(_) | Addr::Abstract(_) => UNIX,
+        };
+        let mut spec = Spec {
+            addr: addr,
+            nonblock: false,
+            backlog: 128,
+            opts: vec![],
+            mode: None,
+            owner: None,
+            group: None,
+        };
+        for subarg in chunks {
+            let (key, value) = match subarg.find('=') {
+                Some(i) => (&subarg[..i], Some(&subarg[i + 1..])),
+                None => (subarg, None),
+            };
+            // The short forms from einhorn
+            let key = match key {
+                "r" => "reuseaddr",
+                "n" => "nonblock",
+                _ => key,
+            };
+            let applies = match key {
+                "" if value.is_none() => continue,
+                "nonblock" => {
+                    try!(flag(key, value, b));
+                    spec.nonblock = true;
+                    TCP | UDP | UNIX
+                }
+                "backlog" => {
+                    spec.backlog = try!(number(key, value, b));
+                    TCP | UNIX
+                }
+                "mode" => {
+                    let mode = try!(required(key, value, b));
+                    spec.mode = Some(try!(u32::from_str_radix(mode, 8)
+                        .map_err(|_| format!("Invalid socket mode '{}' (expected octal)", mode))));
+                    UNIX
+                }
+                "owner" => {
+                    spec.owner = Some(try!(lookup_user(try!(required(key, value, b)))));
+                    UNIX
+                }
+                "group" => {
+                    spec.group = Some(try!(lookup_group(try!(required(key, value, b)))));
+                    UNIX
+                }
+                _ => {
+                    match SOCK_OPTS.iter().find(|o| o.0 == key) {
+                        Some(&(name, level, opt, numeric, applies)) => {
+                            let value = if numeric {
+                                try!(number(name, value, b))
+                            } else {
+                                try!(flag(name, value, b));
+                                1
+                            };
+                            spec.opts.push((name, level, opt, value));
+                            applies
+                        }
+                        None => {
+                            return Err(format!("Unknown socket arg '{}' in '{}'. Try --help for \
+                                                the list of options",
+                                               subarg,
+                                               b))
+                        }
+                    }
+                }
+            };
+            if applies & kind == 0 {
+                return Err(format!("'{}' doesn't apply to {} sockets ('{}')",
+                                   key,
+                                   spec.addr.kind(),
+                                   b));
+            }
+            if key == "v6only" {
+                match spec.addr {
+                    Addr::Tcp(SocketAddr::V6(_)) |
+                    Addr::Udp(SocketAddr::V6(_)) => (),
+                    _ => return Err(format!("'v6only' only applies to IPv6 addresses ('{}')", b)),
+                }
+            }
+        }
+        let has_perms = spec.mode.is_some() || spec.owner.is_some() || spec.group.is_some();
+        if let Addr::Abstract(_) = spec.addr {
+            if has_perms {
+                return Err(format!("Abstract unix sockets have no 'mode', 'owner' or 'group' \
+                                    ('{}')",
+                                   b));
+            }
+        }
+        specs.push(spec);
+    }
+    Ok(specs)
+}
+
+// The value of an option that needs one
+fn required<'a>(key: &str, value: Option<&'a str>, spec: &str) -> Result<&'a str, String> {
+    match value {
+        Some(v) if !v.is_empty() => Ok(v),
+        _ => Err(format!("'{}' needs a value ('{}')", key, spec)),
+    }
+}
+
+fn number(key: &str, value: Option<&str>, spec: &str) -> Result<libc::c_int, String> {
+    let value = try!(required(key, value, spec));
+    match libc::c_int::from_str(value) {
+        Ok(n) if n >= 0 => Ok(n),
+        _ => {
+            Err(format!("Invalid value '{}' for '{}', expected a number ('{}')",
+                        value,
+                        key,
+                        spec))
+        }
+    }
+}
+
+fn flag(key: &str, value: Option<&str>, spec: &str) -> Result<(), String> {
+    match value {
+        None => Ok(()),
+        Some(_) => Err(format!("'{}' doesn't take a value ('{}')", key, spec)),
+    }
+}
+
+fn resolve_inet(addr_str: &str, cfg: &PoolConfig) -> Result<SocketAddr, String> {
+    let sock_addrs = try!(addr_str.to_socket_addrs()
+        .map_err(|e| format!("Couldn't resolve bind address '{}': {}", addr_str, e)));
+    let mut sock_addrs = sock_addrs.filter(|sa| match *sa {
+        SocketAddr::V4(_) => !cfg.ipv6_only,
+        SocketAddr::V6(_) => !cfg.ipv4_only,
+    });
+    match sock_addrs.next() {
+        Some(sa) => Ok(sa),
+        None if cfg.ipv4_only => Err(format!("Couldn't bind '{}' as IPv4", addr_str)),
+        None if cfg.ipv6_only => Err(format!("Couldn't bind '{}' as IPv6", addr_str)),
+        None => Err(format!("Couldn't bind socket '{}'", addr_str)),
+    }
+}
+
+fn lookup_user(user: &str) -> Result<libc::uid_t, String> {
+    if let Ok(uid) = libc::uid_t::from_str(user) {
+        return Ok(uid);
+    }
+    let name = try!(CString::new(user).map_err(|_| format!("Invalid user name '{}'", user)));
+    let pw = unsafe { libc::getpwnam(name.as_ptr()) };
+    if pw.is_null() {
+        return Err(format!("Unknown user '{}'", user));
+    }
+    Ok(unsafe { (*pw).pw_uid })
+}
+
+fn lookup_group(group: &str) -> Result<libc::gid_t, String> {
+    if let Ok(gid) = libc::gid_t::from_str(group) {
+        return Ok(gid);
+    }
+    let name = try!(CString::new(group).map_err(|_| format!("Invalid group name '{}'", group)));
+    let gr = unsafe { libc::getgrnam(name.as_ptr()) };
+    if gr.is_null() {
+        return Err(format!("Unknown group '{}'", group));
+    }
+    Ok(unsafe { (*gr).gr_gid })
+}
+
+// Binds the pool's sockets, returning their (inheritable) file descriptors. Addresses already in
+// use are taken over from the `donors` (see take()) if they have them.
+pub fn bind_all(cfg: &PoolConfig,
+                donors: &[(u32, RawFd)],
+                log: &slog::Logger)
+                -> Result<Vec<RawFd>, String> {
+    let specs = try!(resolve(cfg));
+    let mut fds = vec![];
+    for spec in &specs {
+        match bind(spec, donors, log) {
+            Ok(fd) => fds.push(fd),
+            Err(e) => {
+                for fd in fds {
+                    nix::unistd::close(fd).ok();
+                }
+                return Err(e);
+            }
+        }
+    }
+    Ok(fds)
+}
+
+fn bind(spec: &Spec, donors: &[(u32, RawFd)], log: &slog::Logger) -> Result<RawFd, String> {
+    let in_use = |err: String| if donors.is_empty() {
+        Err(err)
+    } else {
+        take(&spec.addr, donors, log)
+            .map_err(|e| format!("{}, and couldn't take it over from adopted children: {}", err, e))
+    };
+    let busy_file = match spec.addr {
+        Addr::Unix(ref path) => try!(clear_stale(path)),
+        _ => false,
+    };
+    let orig_fd = if busy_file {
+        match in_use(format!("Couldn't bind to {}: already in use", spec.addr)) {
+            Ok(fd) => fd,
+            Err(e) if !donors.is_empty() => {
+                // The adopted children keep the old socket (now without a file) until they're
+                // replaced, so only new connections go to our children
+                error!(log, "binding a fresh socket instead"; "err" => e);
+                if let Addr::Unix(ref path) = spec.addr {
+                    try!(fs::remove_file(path)
+                        .map_err(|e| format!("Couldn't remove socket {}: {}", path.display(), e)));
+                }
+                try!(bind_socket(spec)
+                    .map_err(|e| format!("Couldn't bind to {}: {}", spec.addr, e)))
+            }
+            Err(e) => return Err(e),
+        }
+    } else {
+        match bind_socket(spec) {
+            Ok(fd) => fd,
+            Err(ref e) if e.raw_os_error() == Some(libc::EADDRINUSE) => {
+                try!(in_use(format!("Couldn't bind to {}: {}", spec.addr, e)))
+            }
+            Err(e) => return Err(format!("Couldn't bind to {}: {}", spec.addr, e)),
+        }
+    };
+    // Duplicate, which also clears the CLOEXEC flag
+    let fd = try!(nix::unistd::dup(orig_fd).map_err(|e| format!("Couldn't dup socket: {:?}", e)));
+    nix::unistd::close(orig_fd).ok();
+    if spec.nonblock {
+        nix::fcntl::fcntl(fd, nix::fcntl::FcntlArg::F_SETFL(nix::fcntl::O_NONBLOCK)).unwrap();
+    }
+    debug!(log, "bound socket";
+        "addr" => spec.addr.to_string(),
+        "fd" => fd,
+        "FD_CLOEXEC" => nix::fcntl::fcntl(fd, nix::fcntl::FcntlArg::F_GETFD).unwrap());
+    Ok(fd)
+}
+
+// Options, mode and ownership are all set before listening, so nobody can connect in the meantime
+fn bind_socket(spec: &Spec) -> io::Result<RawFd> {
+    let (family, sock_type) = match spec.addr {
+        Addr::Tcp(SocketAddr::V4(_)) => (AddressFamily::Inet, SockType::Stream),
+        Addr::Tcp(SocketAddr::V6(_)) => (AddressFamily::Inet6, SockType::Stream),
+        Addr::Udp(SocketAddr::V4(_)) => (AddressFamily::Inet, SockType::Datagram),
+        Addr::Udp(SocketAddr::V6(_)) => (AddressFamily::Inet6, SockType::Datagram),
+        Addr::Unix(_) | Addr::Abstract(_) => (AddressFamily::Unix, SockType::Stream),
+    };
+    let fd = try!(nix::sys::socket::socket(family, sock_type, nix::sys::socket::SOCK_CLOEXEC, 0)
+        .map_err(nix_to_io));
+    if let Err(e) = setup_socket(fd, spec) {
+        nix::unistd::close(fd).ok();
+        return Err(e);
+    }
+    Ok(fd)
+}
+
+fn setup_socket(fd: RawFd, spec: &Spec) -> io::Result<()> {
+    if spec.addr.is_tcp() {
+        // As std's TcpListener always did
+        try!(setsockopt(fd, libc::SOL_SOCKET, libc::SO_REUSEADDR, 1));
+    }
+    for &(name, level, opt, value) in &spec.opts {
+        try!(setsockopt(fd, level, opt, value)
+            .map_err(|e| io::Error::new(e.kind(), format!("couldn't set '{}': {}", name, e))));
+    }
+    match spec.addr {
+        Addr::Tcp(sa) | Addr::Udp(sa) => {
+            try!(nix::sys::socket::bind(fd, &SockAddr::Inet(InetAddr::from_std(&sa)))
+                .map_err(nix_to_io));
+        }
+        Addr::Unix(ref path) => {
+            try!(bind_unix(fd, &spec.addr));
+            try!(set_permissions(spec, path));
+        }
+        Addr::Abstract(_) => try!(bind_unix(fd, &spec.addr)),
+    }
+    match spec.addr {
+        Addr::Udp(_) => Ok(()),
+        _ => nix::sys::socket::listen(fd, spec.backlog as usize).map_err(nix_to_io),
+    }
+}
+
+fn setsockopt(fd: RawFd,
+              level: libc::c_int,
+              opt: libc::c_int,
+              value: libc::c_int)
+              -> io::Result<()> {
+    let res = unsafe {
+        libc::setsockopt(fd,
+                         level,
+                         opt,
+                         &value as *const _ as *const libc::c_void,
+                         mem::size_of::<libc::c_int>() as libc::socklen_t)
+    };
+    if res == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+fn bind_unix(fd: RawFd, addr: &Addr) -> io::Result<()> {
+    // Built by hand, since nix gets the length of abstract addresses wrong
+    let mut sun: libc::sockaddr_un = unsafe { mem::zeroed() };
+    sun.sun_family = libc::AF_UNIX as libc::sa_family_t;
+    // Paths are NUL-terminated (by the zeroed byte after them); abstract names start with one
+    let (name, terminator) = match *addr {
+        Addr::Unix(ref path) => (path.as_os_str().as_bytes().to_vec(), 1),
+        Addr::Abstract(ref name) => {
+            let mut bytes = vec![0];
+            bytes.extend_from_slice(name.as_bytes());
+            (bytes, 0)
+        }
+        Addr::Tcp(_) | Addr::Udp(_) => unreachable!(),
+    };
+    if name.len() + terminator > sun.sun_path.len() {
+        return Err(io::Error::from_raw_os_error(libc::ENAMETOOLONG));
+    }
+    for (dst, src) in sun.sun_path.iter_mut().zip(&name) {
+        *dst = *src as libc::c_char;
+    }
+    let len = mem::size_of::<libc::sa_family_t>() + name.len() + terminator;
+    let res = unsafe {
+        libc::bind(fd,
+                   &sun as *const _ as *const libc::sockaddr,
+                   len as libc::socklen_t)
+    };
+    if res == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+fn set_permissions(spec: &Spec, path: &Path) -> io::Result<()> {
+    if let Some(mode) = spec.mode {
+        try!(fs::set_permissions(path, fs::Permissions::from_mode(mode)));
+    }
+    if spec.owner.is_some() || spec.group.is_some() {
+        try!(nix::unistd::chown(path, spec.owner, spec.group).map_err(nix_to_io));
+    }
+    Ok(())
+}
+
+fn nix_to_io(e: nix::Error) -> io::Error {
+    match e {
+        nix::Error::Sys(errno) => io::Error::from_raw_os_error(errno as i32),
+        nix::Error::InvalidPath => io::Error::new(io::ErrorKind::InvalidInput, "invalid path"),
+    }
+}
+
+// Checks whether anything is listening on the socket file at `path`, removing it if not: a socket
+// file nobody listens on is left over from a master that crashed (or a predecessor's children
+// that have since exited). Anything other than a socket is left alone, and is an error.
+fn clear_stale(path: &Path) -> Result<bool, String> {
+    match fs::symlink_metadata(path) {
+        Ok(ref meta) if meta.file_type().is_socket() => (),
+        Ok(_) => return Err(format!("Won't bind to {}: it exists and isn't a socket", path.display())),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(format!("Couldn't check {}: {}", path.display(), e)),
+    }
+    if try!(listening(path).map_err(|e| format!("Couldn't check {}: {}", path.display(), e))) {
+        return Ok(true);
+    }
+    try!(fs::remove_file(path)
+        .map_err(|e| format!("Couldn't remove stale socket {}: {}", path.display(), e)));
+    Ok(false)
+}
+
+// Looks the path up in the kernel's list of listening sockets, rather than connecting to it: a
+// connection would show up as a spurious (empty) client of whichever child accepted it
+fn listening(path: &Path) -> Result<bool, String> {
+    let absolute = |p: &Path| env::current_dir().map(|d| d.join(p)).unwrap_or(p.to_path_buf());
+    let path = absolute(path);
+    Ok(try!(procfs::unix_listeners()).iter().any(|p| absolute(p) == path))
+}
+
+// The paths of those sockets that are unix sockets with a file
+pub fn unix_paths(fds: &[RawFd]) -> Vec<PathBuf> {
+    fds.iter()
+        .filter_map(|&fd| match bound_addr(fd) {
+            Some(Addr::Unix(path)) => Some(path),
+            _ => None,
+        })
+        .collect()
+}
+
+// Removes the socket files that nobody (eg, a child left running) is listening on anymore. Returns
+// the ones still in use.
+pub fn remove_unused(paths: Vec<PathBuf>, log: &slog::Logger) -> Vec<PathBuf> {
+    paths.into_iter()
+        .filter(|path| match clear_stale(path) {
+            Ok(false) => {
+                debug!(log, "removed socket file"; "path" => path.display().to_string());
+                false
+            }
+            Ok(true) => true,
+            Err(e) => {
+                warn!(log, "couldn't clean up socket file"; "err" => e);
+                false
+            }
+        })
+        .collect()
+}
+
+// Closes the sockets on the way out, removing the files of unix sockets that are no longer in use
+pub fn close_all(fds: &[RawFd], log: &slog::Logger) {
+    let paths = unix_paths(fds);
+    for fd in fds {
+        nix::unistd::close(*fd).ok();
+    }
+    for path in remove_unused(paths, log) {
+        info!(log, "socket still in use, not removing it"; "path" => path.display().to_string());
+    }
+}
+
+// The address a socket is bound to
+pub fn bound_addr(fd: RawFd) -> Option<Addr> {
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockname(fd, &mut storage as *mut _ as *mut libc::sockaddr, &mut len)
+    };
+    if res != 0 {
+        return None;
+    }
+    match storage.ss_family as libc::c_int {
+        libc::AF_UNIX => {
+            let un = unsafe { &*(&storage as *const _ as *const libc::sockaddr_un) };
+            // What follows the address family, up to the reported length
+            let path_len = (len as usize).saturating_sub(mem::size_of::<libc::sa_family_t>());
+            let bytes: Vec<u8> = un.sun_path[..path_len].iter().map(|&c| c as u8).collect();
+            match bytes.first() {
+                Some(&0) => Some(Addr::Abstract(String::from_utf8_lossy(&bytes[1..]).into_owned())),
+                Some(_) => {
+                    let path: Vec<u8> = bytes.into_iter().take_while(|&b| b != 0).collect();
+                    Some(Addr::Unix(PathBuf::from(OsString::from_vec(path))))
+                }
+                None => None,
+            }
+        }
+        _ => {
+            // (nix's getsockopt() can't return a SockType safely)
+            let mut sock_type: libc::c_int = 0;
+            let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+            let res = unsafe {
+                libc::getsockopt(fd,
+                                 libc::SOL_SOCKET,
+                                 libc::SO_TYPE,
+                                 &mut sock_type as *mut _ as *mut libc::c_void,
+                                 &mut len)
+            };
+            let datagram = res == 0 && sock_type == libc::SOCK_DGRAM;
+            match nix::sys::socket::getsockname(fd) {
+                Ok(SockAddr::Inet(addr)) if datagram => Some(Addr::Udp(addr.to_std())),
+                Ok(SockAddr::Inet(addr)) => Some(Addr::Tcp(addr.to_std())),
+                _ => None,
+            }
+        }
+    }
+}
+
+// The TCP addresses among the given sockets (eg, for probes)
+pub fn tcp_addrs(fds: &[RawFd]) -> Vec<SocketAddr> {
+    fds.iter()
+        .filter_map(|&fd| match bound_addr(fd) {
+            Some(Addr::Tcp(sa)) => Some(sa),
+            _ => None,
+        })
+        .collect()
+}
+
+// Copies the socket bound to `addr` out of whichever of the `donors` (pid, fd) has it, for taking
+// over from children left running by a crashed master
+fn take(addr: &Addr, donors: &[(u32, RawFd)], log: &slog::Logger) -> Result<RawFd, String> {
+    let mut failure = None;
+    for &(pid, fd) in donors {
+        match pidfd::getfd(pid, fd) {
+            Ok(ours) => {
+                if bound_addr(ours).as_ref() == Some(addr) {
+                    info!(log, "took over socket"; "addr" => addr.to_string(), "from_pid" => pid);
+                    return Ok(ours);
+                }
+                nix::unistd::close(ours).ok();
+            }
+            Err(e) => {
+                error!(log, "couldn't take over socket";
+                    "pid" => pid, "fd" => fd, "err" => e.clone());
+                failure = Some(e);
+            }
+        }
+    }
+    Err(failure.unwrap_or("none of them holds it".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixListener;
+    use std::process;
+    use config;
+
+    fn resolve_binds(binds: &[&str]) -> Result<Vec<Spec>, String> {
+        let mut args = vec![];
+        for b in binds {
+            args.push("--bind".to_string());
+            args.push(b.to_string());
+        }
+        args.push("true".to_string());
+        resolve(&config::load(&args).unwrap().pools[0])
+    }
+
+    fn resolve_one(bind: &str) -> Spec {
+        resolve_binds(&[bind]).unwrap().pop().unwrap()
+    }
+
+    fn resolve_err(bind: &str) -> String {
+        resolve_binds(&[bind]).err().expect(bind)
+    }
+
+    #[test]
+    fn tcp() {
+        let spec = resolve_one("127.0.0.1:8080");
+        assert_eq!(spec.addr, Addr::Tcp(SocketAddr::from_str("127.0.0.1:8080").unwrap()));
+        assert_eq!(spec.backlog, 128);
+        assert!(!spec.nonblock);
+        assert!(spec.opts.is_empty());
+    }
+
+    #[test]
+    fn tcp_options() {
+        let spec = resolve_one("127.0.0.1:8080,r,n,backlog=1024,nodelay,defer_accept=5");
+        assert!(spec.nonblock);
+        assert_eq!(spec.backlog, 1024);
+        let opts: Vec<(&str, libc::c_int)> = spec.opts.iter().map(|o| (o.0, o.3)).collect();
+        assert_eq!(opts, vec![("reuseaddr", 1), ("nodelay", 1), ("defer_accept", 5)]);
+    }
+
+    #[test]
+    fn udp() {
+        let spec = resolve_one("udp:127.0.0.1:8125,rcvbuf=65536");
+        assert_eq!(spec.addr, Addr::Udp(SocketAddr::from_str("127.0.0.1:8125").unwrap()));
+        assert_eq!(spec.addr.kind(), "udp");
+        assert_eq!(spec.opts[0].0, "rcvbuf");
+        assert_eq!(spec.opts[0].3, 65536);
+    }
+
+    #[test]
+    fn unix() {
+        let spec = resolve_one("unix:/run/app.sock,mode=0660,owner=0,group=0");
+        assert_eq!(spec.addr, Addr::Unix(PathBuf::from("/run/app.sock")));
+        assert_eq!(spec.mode, Some(0o660));
+        assert_eq!(spec.owner, Some(0));
+        assert_eq!(spec.group, Some(0));
+        assert_eq!(spec.addr.to_string(), "unix:/run/app.sock");
+    }
+
+    #[test]
+    fn abstract_unix() {
+        let spec = resolve_one("unix:@app,backlog=16");
+        assert_eq!(spec.addr, Addr::Abstract("app".to_string()));
+        assert_eq!(spec.addr.kind(), "unix");
+        assert_eq!(spec.backlog, 16);
+    }
+
+    #[test]
+    fn several() {
+        let specs = resolve_binds(&["127.0.0.1:8080", "unix:@app"]).unwrap();
+        assert_eq!(specs.len(), 2);
+        assert!(specs[0].addr.is_tcp());
+        assert!(!specs[1].addr.is_tcp());
+    }
+
+    #[test]
+    fn unknown_option() {
+        assert!(resolve_err("127.0.0.1:8080,bogus").contains("Unknown socket arg 'bogus'"));
+    }
+
+    #[test]
+    fn bad_port() {
+        assert!(resolve_err("127.0.0.1:99999").contains("Couldn't resolve"));
+        assert!(resolve_err("udp:127.0.0.1").contains("Couldn't resolve"));
+    }
+
+    #[test]
+    fn empty_unix_path() {
+        assert!(resolve_err("unix:").contains("Missing unix socket path"));
+        assert!(resolve_err("unix:@").contains("Missing unix socket path"));
+    }
+
+    #[test]
+    fn bad_values() {
+        assert!(resolve_err("127.0.0.1:8080,backlog").contains("needs a value"));
+        assert!(resolve_err("127.0.0.1:8080,backlog=lots").contains("expected a number"));
+        assert!(resolve_err("127.0.0.1:8080,backlog=-1").contains("expected a number"));
+        assert!(resolve_err("127.0.0.1:8080,nodelay=1").contains("doesn't take a value"));
+        assert!(resolve_err("unix:/run/app.sock,mode=0999").contains("expected octal"));
+    }
+
+    #[test]
+    fn options_for_other_kinds() {
+        assert!(resolve_err("udp:127.0.0.1:8125,nodelay").contains("doesn't apply to udp"));
+        assert!(resolve_err("udp:127.0.0.1:8125,backlog=10").contains("doesn't apply to udp"));
+        assert!(resolve_err("unix:/run/app.sock,reuseport").contains("doesn't apply to unix"));
+        assert!(resolve_err("127.0.0.1:8080,mode=0660").contains("doesn't apply to tcp"));
+        assert!(resolve_err("127.0.0.1:8080,v6only").contains("only applies to IPv6"));
+        assert!(resolve_err("unix:@app,mode=0660").contains("Abstract unix sockets"));
+    }
+
+    #[test]
+    fn stale_unix_socket() {
+        let path = env::temp_dir()
+            .join(format!("einhyrningsins-test-{}-stale.sock", process::id()));
+        fs::remove_file(&path).ok();
+        let listener = UnixListener::bind(&path).unwrap();
+        listener.set_nonblocking(true).unwrap();
+        assert_eq!(clear_stale(&path), Ok(true));
+        // Found without connecting to it
+        assert!(listener.accept().is_err());
+        drop(listener);
+        assert_eq!(clear_stale(&path), Ok(false));
+        assert!(!path.exists());
+    }
+}
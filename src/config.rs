@@ -0,0 +1,929 @@
+//  einhyrningsins: graceful restarts for socket-based daemons
+//  Copyright (C) 2016  Bryan Newbold <bnewbold@robocracy.org>
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU General Public License as published by
+//  the Free Software Foundation, either version 3 of the License, or
+//  (at your option) any later version.
+//
+//  This program is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//  GNU General Public License for more details.
+//
+//  You should have received a copy of the GNU General Public License
+//  along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+// Configuration, from command line options and (optionally) a JSON config file. The file uses the
+// long option names as keys, so anything that can be set on the command line can be set in the
+// file, and vice versa. Command line options win over the file.
+
+use std::io::prelude::*;
+use std::fs::File;
+use std::str::FromStr;
+use getopts;
+use getopts::{Options, Matches};
+use chan_signal::Signal;
+use time::Duration;
+use json;
+use json::JsonValue;
+
+use hook::HookEvent;
+use probe::Probe;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct EinConfig {
+    pub ctrl_path: String,
+    pub config_path: Option<String>,
+    pub verbose: bool,
+    pub syslog: bool,
+    pub log_file: Option<String>,
+    pub forward_usr1: bool,
+    pub subreaper: bool,
+    pub terminate_orphans: bool,
+    pub init_mode: bool,
+    pub state_file: Option<String>,
+    pub adopt: bool,
+    pub pid_file: Option<String>,
+    pub daemonize: bool,
+    pub working_dir: Option<String>,
+    pub kill_on_exit: bool,
+    pub resource_interval: Duration,
+    pub hooks: Vec<(HookEvent, String)>,
+    pub hook_timeout: Duration,
+    pub pools: Vec<PoolConfig>,
+}
+
+// Everything about one named group of identical children
+#[derive(Clone, Debug, PartialEq)]
+pub struct PoolConfig {
+    pub name: String,
+    pub program: String,
+    pub program_args: Vec<String>,
+    pub count: u64,
+    pub childhood: Duration,
+    pub graceperiod: Duration,
+    pub shutdown_steps: Vec<(Signal, Duration)>,
+    pub retries: u64,
+    pub ipv4_only: bool,
+    pub ipv6_only: bool,
+    pub manual_ack: bool,
+    pub bind_slugs: Vec<String>,
+    pub env_drops: Vec<String>,
+    pub env_sets: Vec<(String, String)>,
+    pub probe: Option<Probe>,
+    pub probe_interval: Duration,
+    pub probe_timeout: Duration,
+    pub probe_failures: u64,
+    pub watchdog: Option<Duration>,
+    pub max_rss_mb: Option<u64>,
+    pub max_cpu_secs: Option<u64>,
+    pub max_age: Option<Duration>,
+    pub max_age_jitter: Duration,
+    pub capture_output: bool,
+    pub output_file: Option<String>,
+    pub process_group: bool,
+    pub signal_group: bool,
+    pub pdeathsig: Option<Signal>,
+}
+
+pub fn ein_options() -> Options {
+    let mut opts = Options::new();
+    opts.parsing_style(getopts::ParsingStyle::StopAtFirstFree);
+    opts.optflag("h", "help", "print this help menu");
+    opts.optflag("", "version", "print the version");
+    opts.optopt("c", "config", "read settings from this JSON file", "PATH");
+    opts.optflag("", "check-config", "validate the configuration, then exit");
+    opts.optflag("v", "verbose", "more debugging messages");
+    opts.optflag("", "syslog", "enables syslog-ing (for WARN and above)");
+    opts.optopt("", "log-file", "log to this file instead of stderr (reopened on USR1)", "PATH");
+    opts.optflag("", "no-forward-usr1", "don't pass USR1 on to children");
+    opts.optflag("4", "ipv4-only", "only accept IPv4 connections");
+    opts.optflag("6", "ipv6-only", "only accept IPv6 connections");
+    opts.optflag("m", "manual", "manual (explicit) acknowledge mode");
+    opts.optflag("", "kill-children-on-exit", "KILL children still running when shutdown times out");
+    opts.optflag("", "subreaper", "adopt (and reap) orphaned descendants of children");
+    opts.optflag("", "terminate-orphans", "as --subreaper, and terminate orphans on shutdown");
+    opts.optflag("", "init", "run as PID 1 (eg, a container entrypoint)");
+    opts.optopt("", "state-file", "keep a record of the children in this file", "PATH");
+    opts.optflag("", "adopt", "take over children left running by a crashed master (see --state-file; \
+                               taking their sockets needs permission to ptrace them)");
+    opts.optopt("", "pid-file", "write the master's pid to this (locked) file", "PATH");
+    opts.optflag("", "daemonize", "run in the background, once started up");
+    opts.optopt("", "working-dir", "change to this directory at startup", "PATH");
+    opts.optmulti("", "hook", "command to run on a lifecycle event (can be repeated)", "EVENT:CMD");
+    opts.optopt("", "hook-timeout", "seconds before a hook command is killed (default: 30)", "SECS");
+    opts.optopt("", "pool", "starts the options and program of a named pool (can be repeated)", "NAME");
+    opts.optopt("n", "number", "how many program copies to spawn", "COUNT");
+    opts.optmulti("b", "bind", "socket(s) to bind to (can be repeated)", "ADDR");
+    opts.optmulti("", "drop-env-var", "ENV variables to mask (can be repeated)", "VAR");
+    opts.optmulti("", "set-env-var", "ENV variables to set (can be repeated)", "VAR=VALUE");
+    opts.optopt("d", "socket-path", "where to create the control socket (default: /tmp/einhorn.sock)", "PATH");
+    opts.optopt("r", "retries", "how many times to attempt spawning", "COUNT");
+    opts.optopt("", "watchdog", "replace children that go this long without a heartbeat", "SECS");
+    opts.optopt("", "max-rss", "replace children using more memory than this", "MB");
+    opts.optopt("", "max-cpu-time", "replace children that have used this much CPU time", "SECS");
+    opts.optopt("", "resource-interval", "seconds between resource checks (default: 10)", "SECS");
+    opts.optopt("", "max-child-age", "gracefully replace children older than this", "SECS");
+    opts.optopt("", "max-child-age-jitter", "random extra age per child (default: a tenth of max age)", "SECS");
+    opts.optopt("", "shutdown-signals", "shutdown escalation sequence (default: USR2:3,TERM:3,KILL)", "SIG[:SECS],...");
+    opts.optopt("", "grace-period", "default wait for each shutdown step, and for stragglers at exit (default: 3)", "SECS");
+    opts.optopt("", "childhood", "seconds a new child must stay alive (or has to ack) to be healthy (default: 3)", "SECS");
+    opts.optopt("", "probe", "health probe: tcp[:ADDR], http:[ADDR]/PATH or exec:CMD", "PROBE");
+    opts.optopt("", "probe-interval", "seconds between health probes (default: 5)", "SECS");
+    opts.optopt("", "probe-timeout", "seconds before a probe fails (default: 2)", "SECS");
+    opts.optopt("", "probe-failures", "failed probes before giving up on a child (default: 3)", "COUNT");
+    opts.optflag("", "capture-output", "log each line children write to stdout and stderr");
+    opts.optopt("", "output-file", "append captured child output to this file", "PATH");
+    opts.optflag("", "process-group", "start each child in its own process group, and KILL the whole group");
+    opts.optflag("", "signal-group", "send every signal to each child's whole process group");
+    opts.optopt("", "pdeathsig", "signal children get if the master dies unexpectedly", "SIG");
+    opts
+}
+
+// Options which apply to the master as a whole, rather than to a pool
+pub const GLOBAL_OPTS: &'static [&'static str] = &["help",
+                                                   "version",
+                                                   "config",
+                                                   "check-config",
+                                                   "verbose",
+                                                   "syslog",
+                                                   "log-file",
+                                                   "no-forward-usr1",
+                                                   "socket-path",
+                                                   "kill-children-on-exit",
+                                                   "subreaper",
+                                                   "terminate-orphans",
+                                                   "init",
+                                                   "state-file",
+                                                   "adopt",
+                                                   "pid-file",
+                                                   "daemonize",
+                                                   "working-dir",
+                                                   "resource-interval",
+                                                   "hook",
+                                                   "hook-timeout"];
+
+// Options which apply to each pool. At the top level of the config file (or before the first
+// --pool), these are defaults for every pool.
+const POOL_OPTS: &'static [&'static str] = &["ipv4-only",
+                                             "ipv6-only",
+                                             "manual",
+                                             "number",
+                                             "bind",
+                                             "drop-env-var",
+                                             "set-env-var",
+                                             "retries",
+                                             "watchdog",
+                                             "max-rss",
+                                             "max-cpu-time",
+                                             "max-child-age",
+                                             "max-child-age-jitter",
+                                             "shutdown-signals",
+                                             "grace-period",
+                                             "childhood",
+                                             "probe",
+                                             "probe-interval",
+                                             "probe-timeout",
+                                             "probe-failures",
+                                             "capture-output",
+                                             "output-file",
+                                             "process-group",
+                                             "signal-group",
+                                             "pdeathsig"];
+
+const FLAG_OPTS: &'static [&'static str] = &["verbose",
+                                             "syslog",
+                                             "no-forward-usr1",
+                                             "kill-children-on-exit",
+                                             "subreaper",
+                                             "terminate-orphans",
+                                             "init",
+                                             "adopt",
+                                             "daemonize",
+                                             "ipv4-only",
+                                             "ipv6-only",
+                                             "manual",
+                                             "capture-output",
+                                             "process-group",
+                                             "signal-group"];
+
+const MULTI_OPTS: &'static [&'static str] = &["hook", "bind", "drop-env-var", "set-env-var"];
+
+pub fn signal_from_str(name: &str) -> Option<Signal> {
+    match name {
+        "SIGHUP"  | "HUP"  | "hup"  => Some(Signal::HUP),
+        "SIGINT"  | "INT"  | "int"  => Some(Signal::INT),
+        "SIGQUIT" | "QUIT" | "quit" => Some(Signal::QUIT),
+        "SIGTERM" | "TERM" | "term" => Some(Signal::TERM),
+        "SIGTTIN" | "TTIN" | "ttin" => Some(Signal::TTIN),
+        "SIGTTOU" | "TTOU" | "ttou" => Some(Signal::TTOU),
+        "SIGKILL" | "KILL" | "kill" => Some(Signal::KILL),
+        "SIGUSR1" | "USR1" | "usr1" => Some(Signal::USR1),
+        "SIGUSR2" | "USR2" | "usr2" => Some(Signal::USR2),
+        "SIGSTOP" | "STOP" | "stop" => Some(Signal::STOP),
+        "SIGCONT" | "CONT" | "cont" => Some(Signal::CONT),
+        _ => None,
+    }
+}
+
+// One source of settings: parsed command line options, or an object from the config file
+#[derive(Copy, Clone)]
+enum Layer<'a> {
+    Args(&'a Matches),
+    File(&'a JsonValue),
+}
+
+impl<'a> Layer<'a> {
+    fn opt(&self, name: &str) -> Option<String> {
+        match *self {
+            Layer::Args(m) => m.opt_str(name),
+            Layer::File(j) => {
+                match j[name] {
+                    JsonValue::Null => None,
+                    ref v if v.is_string() => v.as_str().map(String::from),
+                    // Numbers
+                    ref v => Some(v.dump()),
+                }
+            }
+        }
+    }
+
+    // None if the layer doesn't say either way
+    fn flag(&self, name: &str) -> Option<bool> {
+        match *self {
+            Layer::Args(m) if m.opt_present(name) => Some(true),
+            Layer::Args(_) => None,
+            Layer::File(j) => j[name].as_bool(),
+        }
+    }
+
+    fn multi(&self, name: &str) -> Option<Vec<String>> {
+        match *self {
+            Layer::Args(m) if m.opt_present(name) => Some(m.opt_strs(name)),
+            Layer::Args(_) => None,
+            Layer::File(j) => {
+                match j[name] {
+                    JsonValue::Null => None,
+                    // Environment variables can also be given as an object
+                    JsonValue::Object(_) => {
+                        Some(j[name]
+                            .entries()
+                            .map(|(k, v)| format!("{}={}", k, v.as_str().unwrap_or("")))
+                            .collect())
+                    }
+                    _ => {
+                        Some(j[name]
+                            .members()
+                            .filter_map(|v| v.as_str())
+                            .map(String::from)
+                            .collect())
+                    }
+                }
+            }
+        }
+    }
+
+    // The program and its arguments, if given
+    fn program(&self) -> Option<Vec<String>> {
+        match *self {
+            Layer::Args(m) if !m.free.is_empty() => Some(m.free.clone()),
+            Layer::Args(_) => None,
+            Layer::File(j) => {
+                j["program"].as_str().map(|p| {
+                    let mut program = vec![p.to_string()];
+                    program.extend(j["args"].members().filter_map(|a| a.as_str()).map(String::from));
+                    program
+                })
+            }
+        }
+    }
+}
+
+// Settings are looked up in each layer in turn, so earlier layers override later ones
+struct Settings<'a> {
+    layers: Vec<Layer<'a>>,
+}
+
+impl<'a> Settings<'a> {
+    fn opt(&self, name: &str) -> Option<String> {
+        self.layers.iter().filter_map(|l| l.opt(name)).next()
+    }
+
+    fn flag(&self, name: &str) -> bool {
+        self.layers.iter().filter_map(|l| l.flag(name)).next().unwrap_or(false)
+    }
+
+    // Lists override as a whole, like everything else; an empty list in the file clears one
+    fn multi(&self, name: &str) -> Vec<String> {
+        self.layers.iter().filter_map(|l| l.multi(name)).next().unwrap_or_else(Vec::new)
+    }
+
+    fn num(&self, name: &str) -> Result<Option<u64>, String> {
+        match self.opt(name) {
+            Some(n) => {
+                u64::from_str(&n)
+                    .map(Some)
+                    .map_err(|_| format!("{} should be an integer, not '{}'", name, n))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn secs(&self, name: &str) -> Result<Option<Duration>, String> {
+        self.num(name).map(|n| n.map(|n| Duration::seconds(n as i64)))
+    }
+
+    // Like secs(), for settings where zero makes no sense
+    fn nonzero_secs(&self, name: &str) -> Result<Option<Duration>, String> {
+        match try!(self.num(name)) {
+            Some(0) => Err(format!("{} should be at least 1 second", name)),
+            n => Ok(n.map(|n| Duration::seconds(n as i64))),
+        }
+    }
+}
+
+// Parses the command line (without the program name), reading the config file it names if any.
+pub fn load(args: &[String]) -> Result<EinConfig, String> {
+    let opts = ein_options();
+    let parse = |args: &[String]| opts.parse(args).map_err(|f| f.to_string());
+
+    // Named pools split the command line into segments, each starting with "--pool NAME". The
+    // leading segment holds the global options, plus defaults for every pool. If the leading
+    // segment already names a program, there is just the one pool, and any "--pool" after that
+    // is one of the program's own arguments.
+    let pool_starts: Vec<usize> = args.iter()
+        .enumerate()
+        .filter(|&(_, a)| a == "--pool")
+        .map(|(i, _)| i)
+        .collect();
+    let leading_end = pool_starts.first().cloned().unwrap_or(args.len());
+    let mut matches = try!(parse(&args[..leading_end]));
+    let cli_pooled = matches.free.is_empty() && !pool_starts.is_empty();
+    if !cli_pooled {
+        matches = try!(parse(args));
+    }
+    let mut cli_pools: Vec<(String, Matches)> = vec![];
+    if cli_pooled {
+        for (i, &start) in pool_starts.iter().enumerate() {
+            let end = pool_starts.get(i + 1).cloned().unwrap_or(args.len());
+            let name = match args.get(start + 1) {
+                Some(name) if start + 1 < end && !name.is_empty() => name.clone(),
+                _ => return Err("Missing name after --pool".to_string()),
+            };
+            if cli_pools.iter().any(|&(ref n, _)| *n == name) {
+                return Err(format!("Duplicate pool name '{}'", name));
+            }
+            let pool_matches = try!(parse(&args[start + 2..end]));
+            for opt in GLOBAL_OPTS {
+                if pool_matches.opt_present(opt) {
+                    return Err(format!("--{} applies to the master, so must come before the \
+                                        first --pool",
+                                       opt));
+                }
+            }
+            cli_pools.push((name, pool_matches));
+        }
+    }
+
+    let config_path = matches.opt_str("config");
+    let file = match config_path {
+        Some(ref path) => Some(try!(read_config_file(path))),
+        None => None,
+    };
+    let file_pools: Vec<&JsonValue> = file.as_ref().map_or(vec![], |f| f["pools"].members().collect());
+
+    let mut top = vec![Layer::Args(&matches)];
+    if let Some(ref f) = file {
+        top.push(Layer::File(f));
+    }
+    let settings = Settings { layers: top.clone() };
+
+    let mut hooks = vec![];
+    for spec in settings.multi("hook") {
+        hooks.push(try!(HookEvent::parse(&spec)));
+    }
+
+    // Each pool gets its own settings (from the command line and the file), on top of the
+    // top-level defaults
+    let mut pools = vec![];
+    if cli_pools.is_empty() && file_pools.is_empty() {
+        pools.push(try!(parse_pool_config("default", &settings, &settings)));
+    } else {
+        if !matches.free.is_empty() {
+            return Err("The config file has pools, so the program must be given per pool"
+                .to_string());
+        }
+        if !settings.multi("bind").is_empty() {
+            return Err("With pools, sockets must be bound per pool".to_string());
+        }
+        let mut names: Vec<String> = vec![];
+        for p in &file_pools {
+            match p["name"].as_str() {
+                Some(name) if names.iter().any(|n| n == name) => {
+                    return Err(format!("Duplicate pool name '{}'", name))
+                }
+                Some(name) => names.push(name.to_string()),
+                None => return Err("Pool in config file is missing a name".to_string()),
+            }
+        }
+        for &(ref name, _) in &cli_pools {
+            if !names.contains(name) {
+                names.push(name.clone());
+            }
+        }
+        for name in names {
+            let mut own = vec![];
+            if let Some(&(_, ref m)) = cli_pools.iter().find(|&&(ref n, _)| *n == name) {
+                own.push(Layer::Args(m));
+            }
+            if let Some(p) = file_pools.iter().find(|p| p["name"].as_str() == Some(&name)) {
+                own.push(Layer::File(p));
+            }
+            let mut layers = own.clone();
+            layers.extend(top.iter().cloned());
+            pools.push(try!(parse_pool_config(&name,
+                                              &Settings { layers: layers },
+                                              &Settings { layers: own })));
+        }
+    }
+
+    if settings.flag("adopt") && settings.opt("state-file").is_none() {
+        return Err("--adopt needs a --state-file to adopt from".to_string());
+    }
+    if settings.flag("daemonize") && settings.flag("init") {
+        return Err("Can't daemonize when running as init".to_string());
+    }
+
+    Ok(EinConfig {
+        ctrl_path: settings.opt("socket-path").unwrap_or("/tmp/einhorn.sock".to_string()),
+        config_path: config_path,
+        verbose: settings.flag("verbose"),
+        syslog: settings.flag("syslog"),
+        log_file: settings.opt("log-file"),
+        forward_usr1: !settings.flag("no-forward-usr1"),
+        subreaper: settings.flag("subreaper") || settings.flag("terminate-orphans") ||
+                   settings.flag("init"),
+        terminate_orphans: settings.flag("terminate-orphans"),
+        init_mode: settings.flag("init"),
+        state_file: settings.opt("state-file"),
+        adopt: settings.flag("adopt"),
+        pid_file: settings.opt("pid-file"),
+        daemonize: settings.flag("daemonize"),
+        working_dir: settings.opt("working-dir"),
+        kill_on_exit: settings.flag("kill-children-on-exit") || settings.flag("init"),
+        resource_interval: try!(settings.secs("resource-interval"))
+            .unwrap_or(Duration::seconds(10)),
+        hooks: hooks,
+        hook_timeout: try!(settings.secs("hook-timeout")).unwrap_or(Duration::seconds(30)),
+        pools: pools,
+    })
+}
+
+fn read_config_file(path: &str) -> Result<JsonValue, String> {
+    let mut raw = String::new();
+    try!(File::open(path)
+        .and_then(|mut f| f.read_to_string(&mut raw))
+        .map_err(|e| format!("Couldn't read config file {}: {}", path, e)));
+    let file = try!(json::parse(&raw).map_err(|e| format!("Invalid config file {}: {}", path, e)));
+    if !file.is_object() {
+        return Err(format!("Invalid config file {}: expected a JSON object", path));
+    }
+    try!(check_keys(&file, false).map_err(|e| format!("Invalid config file {}: {}", path, e)));
+    for p in file["pools"].members() {
+        try!(check_keys(p, true).map_err(|e| format!("Invalid config file {}: {}", path, e)));
+    }
+    if !file["pools"].is_null() && !file["pools"].is_array() {
+        return Err(format!("Invalid config file {}: 'pools' should be a list", path));
+    }
+    Ok(file)
+}
+
+// Catches typos and values of the wrong type, which would otherwise be quietly ignored
+fn check_keys(obj: &JsonValue, is_pool: bool) -> Result<(), String> {
+    if !obj.is_object() {
+        return Err("expected a JSON object".to_string());
+    }
+    for (key, value) in obj.entries() {
+        let known = POOL_OPTS.contains(&key) || key == "program" || key == "args" ||
+                    if is_pool {
+            key == "name"
+        } else {
+            key == "pools" || (GLOBAL_OPTS.contains(&key) && key != "help" && key != "version" &&
+                               key != "config" && key != "check-config")
+        };
+        if !known {
+            return Err(if is_pool && GLOBAL_OPTS.contains(&key) {
+                format!("'{}' applies to the master, so can't be set per pool", key)
+            } else {
+                format!("unknown setting '{}'", key)
+            });
+        }
+        let ok = if FLAG_OPTS.contains(&key) {
+            value.is_boolean()
+        } else if MULTI_OPTS.contains(&key) || key == "args" {
+            value.is_array() && value.members().all(|v| v.is_string()) ||
+            (key == "set-env-var" && value.is_object() && value.entries().all(|(_, v)| v.is_string()))
+        } else if key == "pools" {
+            value.is_array()
+        } else {
+            value.is_string() || value.is_number()
+        };
+        if !ok {
+            return Err(format!("wrong type of value for '{}'", key));
+        }
+    }
+    Ok(())
+}
+
+// Builds one pool's configuration. `own` holds just the settings given for this pool
+// specifically (as opposed to defaults for every pool), which is where its program and sockets
+// have to come from.
+fn parse_pool_config(name: &str, settings: &Settings, own: &Settings) -> Result<PoolConfig, String> {
+
+    if settings.flag("ipv4-only") && settings.flag("ipv6-only") {
+        return Err("Can't be both IPv4-only and IPv6-only".to_string());
+    }
+
+    let probe = match settings.opt("probe") {
+        Some(p) => Some(try!(Probe::parse(&p))),
+        None => None,
+    };
+
+    let max_age = try!(settings.secs("max-child-age"));
+    let max_age_jitter = match try!(settings.secs("max-child-age-jitter")) {
+        Some(jitter) => jitter,
+        None => max_age.map(|a| a / 10).unwrap_or(Duration::zero()),
+    };
+
+    let graceperiod = try!(settings.nonzero_secs("grace-period")).unwrap_or(Duration::seconds(3));
+    let shutdown_steps = try!(parse_shutdown_steps(&settings.opt("shutdown-signals")
+                                                       .unwrap_or("USR2,TERM,KILL".to_string()),
+                                                   graceperiod));
+
+    let pdeathsig = match settings.opt("pdeathsig") {
+        Some(name) => {
+            match signal_from_str(&name) {
+                Some(sig) => Some(sig),
+                None => return Err(format!("Unknown signal '{}' for pdeathsig", name)),
+            }
+        }
+        None => None,
+    };
+
+    let mut env_sets = vec![];
+    for var in settings.multi("set-env-var") {
+        let mut chunks = var.splitn(2, '=');
+        match (chunks.next(), chunks.next()) {
+            (Some(name), Some(value)) if !name.is_empty() => {
+                env_sets.push((name.to_string(), value.to_string()))
+            }
+            _ => return Err(format!("Invalid env var '{}'; expected VAR=VALUE", var)),
+        }
+    }
+
+    let mut program_and_args = match own.layers.iter().filter_map(|l| l.program()).next() {
+        Some(p) => p.into_iter(),
+        None if name == "default" => return Err("Missing program to run (try --help)".to_string()),
+        None => return Err(format!("Missing program to run for pool '{}' (try --help)", name)),
+    };
+
+    Ok(PoolConfig {
+        name: name.to_string(),
+        program: program_and_args.next().unwrap(), // safe
+        program_args: program_and_args.collect(),
+        count: try!(settings.num("number")).unwrap_or(1),
+        childhood: try!(settings.nonzero_secs("childhood")).unwrap_or(Duration::seconds(3)),
+        graceperiod: graceperiod,
+        shutdown_steps: shutdown_steps,
+        retries: try!(settings.num("retries")).unwrap_or(1),
+        ipv4_only: settings.flag("ipv4-only"),
+        ipv6_only: settings.flag("ipv6-only"),
+        manual_ack: settings.flag("manual"),
+        bind_slugs: own.multi("bind"),
+        env_drops: settings.multi("drop-env-var"),
+        env_sets: env_sets,
+        probe: probe,
+        probe_interval: try!(settings.secs("probe-interval")).unwrap_or(Duration::seconds(5)),
+        probe_timeout: try!(settings.nonzero_secs("probe-timeout")).unwrap_or(Duration::seconds(2)),
+        probe_failures: try!(settings.num("probe-failures")).unwrap_or(3),
+        watchdog: try!(settings.secs("watchdog")),
+        max_rss_mb: try!(settings.num("max-rss")),
+        max_cpu_secs: try!(settings.num("max-cpu-time")),
+        max_age: max_age,
+        max_age_jitter: max_age_jitter,
+        capture_output: settings.flag("capture-output") || settings.opt("output-file").is_some(),
+        output_file: settings.opt("output-file"),
+        process_group: settings.flag("process-group") || settings.flag("signal-group"),
+        signal_group: settings.flag("signal-group"),
+        pdeathsig: pdeathsig,
+    })
+}
+
+// Parses a shutdown escalation sequence like "QUIT:30,TERM:5,KILL". Steps without an explicit
+// wait get the default graceperiod.
+pub fn parse_shutdown_steps(seq: &str,
+                            graceperiod: Duration)
+                            -> Result<Vec<(Signal, Duration)>, String> {
+    let mut steps = vec![];
+    for step in seq.split(',') {
+        let mut chunks = step.splitn(2, ':');
+        let name = chunks.next().unwrap(); // safe
+        let sig = match signal_from_str(name) {
+            Some(sig) => sig,
+            None => return Err(format!("Unknown signal '{}' in shutdown sequence", name)),
+        };
+        let wait = match chunks.next() {
+            Some(secs) => match u64::from_str(secs) {
+                Ok(secs) => Duration::seconds(secs as i64),
+                Err(_) => return Err(format!("Invalid wait '{}' in shutdown sequence", secs)),
+            },
+            None => graceperiod,
+        };
+        steps.push((sig, wait));
+    }
+    Ok(steps)
+}
+
+// Where to start the shutdown sequence to terminate a child outright: the first TERM or KILL
+// step, or the very first step if there is neither
+pub fn terminate_step(steps: &[(Signal, Duration)]) -> usize {
+    steps.iter()
+        .position(|&(sig, _)| sig == Signal::TERM || sig == Signal::KILL)
+        .unwrap_or(0)
+}
+
+// Human-readable settings, for logging what a reload changed
+pub fn describe(cfg: &EinConfig) -> Vec<(&'static str, String)> {
+    vec![("socket-path", cfg.ctrl_path.clone()),
+         ("verbose", cfg.verbose.to_string()),
+         ("syslog", cfg.syslog.to_string()),
+         ("log-file", cfg.log_file.clone().unwrap_or(String::new())),
+         ("no-forward-usr1", (!cfg.forward_usr1).to_string()),
+         ("subreaper", cfg.subreaper.to_string()),
+         ("terminate-orphans", cfg.terminate_orphans.to_string()),
+         ("init", cfg.init_mode.to_string()),
+         ("state-file", cfg.state_file.clone().unwrap_or(String::new())),
+         ("pid-file", cfg.pid_file.clone().unwrap_or(String::new())),
+         ("daemonize", cfg.daemonize.to_string()),
+         ("working-dir", cfg.working_dir.clone().unwrap_or(String::new())),
+         ("kill-children-on-exit", cfg.kill_on_exit.to_string()),
+         ("resource-interval", secs(&cfg.resource_interval)),
+         ("hook",
+          cfg.hooks.iter().map(|&(e, ref cmd)| format!("{}:{}", e.name(), cmd)).collect::<Vec<_>>().join(" ")),
+         ("hook-timeout", secs(&cfg.hook_timeout))]
+}
+
+pub fn describe_pool(cfg: &PoolConfig) -> Vec<(&'static str, String)> {
+    let mut program = vec![cfg.program.clone()];
+    program.extend(cfg.program_args.iter().cloned());
+    vec![("program", program.join(" ")),
+         ("number", cfg.count.to_string()),
+         ("bind", cfg.bind_slugs.join(" ")),
+         ("ipv4-only", cfg.ipv4_only.to_string()),
+         ("ipv6-only", cfg.ipv6_only.to_string()),
+         ("manual", cfg.manual_ack.to_string()),
+         ("retries", cfg.retries.to_string()),
+         ("drop-env-var", cfg.env_drops.join(" ")),
+         ("set-env-var",
+          cfg.env_sets.iter().map(|&(ref k, ref v)| format!("{}={}", k, v)).collect::<Vec<_>>().join(" ")),
+         ("shutdown-signals",
+          cfg.shutdown_steps
+              .iter()
+              .map(|&(sig, ref wait)| format!("{:?}:{}", sig, secs(wait)))
+              .collect::<Vec<_>>()
+              .join(",")),
+         ("grace-period", secs(&cfg.graceperiod)),
+         ("childhood", secs(&cfg.childhood)),
+         ("probe", cfg.probe.as_ref().map_or(String::new(), |p| format!("{:?}", p))),
+         ("probe-interval", secs(&cfg.probe_interval)),
+         ("probe-timeout", secs(&cfg.probe_timeout)),
+         ("probe-failures", cfg.probe_failures.to_string()),
+         ("watchdog", cfg.watchdog.as_ref().map_or(String::new(), secs)),
+         ("max-rss", cfg.max_rss_mb.map_or(String::new(), |n| n.to_string())),
+         ("max-cpu-time", cfg.max_cpu_secs.map_or(String::new(), |n| n.to_string())),
+         ("max-child-age", cfg.max_age.as_ref().map_or(String::new(), secs)),
+         ("max-child-age-jitter", secs(&cfg.max_age_jitter)),
+         ("capture-output", cfg.capture_output.to_string()),
+         ("output-file", cfg.output_file.clone().unwrap_or(String::new())),
+         ("process-group", cfg.process_group.to_string()),
+         ("signal-group", cfg.signal_group.to_string()),
+         ("pdeathsig", cfg.pdeathsig.map_or(String::new(), |sig| format!("{:?}", sig)))]
+}
+
+// The settings which differ, as (setting, old, new)
+pub fn diff(old: &[(&'static str, String)],
+            new: &[(&'static str, String)])
+            -> Vec<(&'static str, String, String)> {
+    old.iter()
+        .zip(new.iter())
+        .filter(|&(o, n)| o.1 != n.1)
+        .map(|(o, n)| (o.0, o.1.clone(), n.1.clone()))
+        .collect()
+}
+
+fn secs(d: &Duration) -> String {
+    d.num_seconds().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    // Loads the command line `cli`, with "--config" pointing at a file holding `file`
+    fn load_with_file(name: &str, file: &str, cli: &[&str]) -> Result<EinConfig, String> {
+        let path = env::temp_dir()
+            .join(format!("einhyrningsins-test-{}-{}.json", process::id(), name));
+        File::create(&path).and_then(|mut f| f.write_all(file.as_bytes())).unwrap();
+        let mut all = args(&["--config", path.to_str().unwrap()]);
+        all.extend(args(cli));
+        let cfg = load(&all);
+        fs::remove_file(&path).ok();
+        cfg
+    }
+
+    #[test]
+    fn defaults() {
+        let cfg = load(&args(&["prog", "--flag"])).unwrap();
+        assert_eq!(cfg.ctrl_path, "/tmp/einhorn.sock");
+        assert_eq!(cfg.pools.len(), 1);
+        let pool = &cfg.pools[0];
+        assert_eq!(pool.name, "default");
+        assert_eq!(pool.program, "prog");
+        assert_eq!(pool.program_args, vec!["--flag".to_string()]);
+        assert_eq!(pool.count, 1);
+        assert_eq!(pool.childhood, Duration::seconds(3));
+        assert_eq!(pool.graceperiod, Duration::seconds(3));
+        assert_eq!(pool.shutdown_steps, steps("USR2,TERM,KILL"));
+    }
+
+    #[test]
+    fn durations() {
+        let cfg = load(&args(&["--grace-period", "7", "--childhood", "2", "--shutdown-signals",
+                               "QUIT,KILL", "prog"]))
+            .unwrap();
+        let pool = &cfg.pools[0];
+        assert_eq!(pool.graceperiod, Duration::seconds(7));
+        assert_eq!(pool.childhood, Duration::seconds(2));
+        assert_eq!(pool.shutdown_steps,
+                   vec![(Signal::QUIT, Duration::seconds(7)), (Signal::KILL, Duration::seconds(7))]);
+    }
+
+    #[test]
+    fn bad_durations() {
+        assert!(load(&args(&["--probe-interval", "soon", "prog"])).is_err());
+        assert!(load(&args(&["--watchdog", "-5", "prog"])).is_err());
+        assert!(load(&args(&["--childhood", "0", "prog"])).is_err());
+        assert!(load(&args(&["--grace-period", "0", "prog"])).is_err());
+        assert!(load(&args(&["--probe-timeout", "0", "prog"])).is_err());
+        assert!(load(&args(&["--probe-timeout", "-1", "prog"])).is_err());
+        assert!(load(&args(&["--shutdown-signals", "TERM:soon", "prog"])).is_err());
+    }
+
+    #[test]
+    fn missing_program() {
+        assert!(load(&args(&["-n", "2"])).is_err());
+    }
+
+    #[test]
+    fn file_only() {
+        let cfg = load_with_file("file_only",
+                                 r#"{"program": "prog", "args": ["a", "b"], "number": 3,
+                                     "childhood": 5, "verbose": true}"#,
+                                 &[])
+            .unwrap();
+        assert!(cfg.verbose);
+        assert_eq!(cfg.pools[0].program, "prog");
+        assert_eq!(cfg.pools[0].program_args, args(&["a", "b"]));
+        assert_eq!(cfg.pools[0].count, 3);
+        assert_eq!(cfg.pools[0].childhood, Duration::seconds(5));
+    }
+
+    #[test]
+    fn command_line_wins() {
+        let cfg = load_with_file("command_line_wins",
+                                 r#"{"program": "prog", "number": 3, "probe-interval": 7,
+                                     "socket-path": "/tmp/file.sock",
+                                     "bind": ["127.0.0.1:8080"]}"#,
+                                 &["-n", "5", "--socket-path", "/tmp/cli.sock", "--bind",
+                                   "127.0.0.1:8081", "other"])
+            .unwrap();
+        assert_eq!(cfg.ctrl_path, "/tmp/cli.sock");
+        let pool = &cfg.pools[0];
+        assert_eq!(pool.program, "other");
+        assert_eq!(pool.count, 5);
+        assert_eq!(pool.probe_interval, Duration::seconds(7));
+        // Lists are replaced, not added to
+        assert_eq!(pool.bind_slugs, args(&["127.0.0.1:8081"]));
+    }
+
+    #[test]
+    fn lists_from_file_unless_given() {
+        let file = r#"{"drop-env-var": ["A", "B"],
+                       "pools": [{"name": "web", "program": "web", "drop-env-var": []},
+                                 {"name": "jobs", "program": "jobs"}]}"#;
+        let cfg = load_with_file("lists_from_file_unless_given", file, &[]).unwrap();
+        assert!(cfg.pools[0].env_drops.is_empty());
+        assert_eq!(cfg.pools[1].env_drops, args(&["A", "B"]));
+        let cfg = load_with_file("lists_from_file_unless_given",
+                                 file,
+                                 &["--drop-env-var", "C"])
+            .unwrap();
+        assert!(cfg.pools[0].env_drops.is_empty());
+        assert_eq!(cfg.pools[1].env_drops, args(&["C"]));
+    }
+
+    #[test]
+    fn file_pools() {
+        let cfg = load_with_file("file_pools",
+                                 r#"{"number": 2, "retries": 4,
+                                     "pools": [{"name": "web", "program": "web"},
+                                               {"name": "jobs", "program": "jobs",
+                                                "number": 1}]}"#,
+                                 &["--pool", "web", "-n", "6"])
+            .unwrap();
+        assert_eq!(cfg.pools.len(), 2);
+        assert_eq!(cfg.pools[0].name, "web");
+        assert_eq!(cfg.pools[0].count, 6);
+        assert_eq!(cfg.pools[0].retries, 4);
+        assert_eq!(cfg.pools[1].name, "jobs");
+        assert_eq!(cfg.pools[1].count, 1);
+        assert_eq!(cfg.pools[1].retries, 4);
+    }
+
+    #[test]
+    fn invalid_files() {
+        assert!(load_with_file("not_json", "{", &["prog"]).is_err());
+        assert!(load_with_file("not_object", "[]", &["prog"]).is_err());
+        assert!(load_with_file("unknown", r#"{"numbr": 2}"#, &["prog"])
+            .unwrap_err()
+            .contains("unknown setting 'numbr'"));
+        assert!(load_with_file("wrong_type", r#"{"verbose": "yes"}"#, &["prog"])
+            .unwrap_err()
+            .contains("wrong type"));
+        assert!(load_with_file("bad_duration", r#"{"grace-period": "soon"}"#, &["prog"]).is_err());
+        assert!(load_with_file("global_in_pool",
+                               r#"{"pools": [{"name": "web", "program": "web",
+                                              "pid-file": "/tmp/x.pid"}]}"#,
+                               &[])
+            .unwrap_err()
+            .contains("applies to the master"));
+        assert!(load_with_file("duplicate_pool",
+                               r#"{"pools": [{"name": "web", "program": "web"},
+                                             {"name": "web", "program": "web"}]}"#,
+                               &[])
+            .unwrap_err()
+            .contains("Duplicate pool name"));
+    }
+
+    fn steps(seq: &str) -> Vec<(Signal, Duration)> {
+        parse_shutdown_steps(seq, Duration::seconds(3)).unwrap()
+    }
+
+    #[test]
+    fn shutdown_steps() {
+        assert_eq!(steps("QUIT:30,TERM:5,KILL"),
+                   vec![(Signal::QUIT, Duration::seconds(30)),
+                        (Signal::TERM, Duration::seconds(5)),
+                        (Signal::KILL, Duration::seconds(3))]);
+        assert_eq!(steps("SIGUSR2:0,kill"),
+                   vec![(Signal::USR2, Duration::zero()), (Signal::KILL, Duration::seconds(3))]);
+    }
+
+    #[test]
+    fn shutdown_steps_invalid() {
+        let parse = |seq| parse_shutdown_steps(seq, Duration::seconds(3));
+        assert!(parse("").is_err());
+        assert!(parse("TERM,").is_err());
+        assert!(parse("TREM:5").is_err());
+        assert!(parse("TERM:").is_err());
+        assert!(parse("TERM:-1").is_err());
+        assert!(parse("TERM:1.5").is_err());
+        assert!(parse("TERM:5s").is_err());
+    }
+
+    #[test]
+    fn terminate_skips_graceful_steps() {
+        assert_eq!(terminate_step(&steps("USR2,TERM,KILL")), 1);
+        assert_eq!(terminate_step(&steps("QUIT:30,USR2:5,KILL")), 2);
+    }
+
+    #[test]
+    fn terminate_starts_at_custom_term() {
+        assert_eq!(terminate_step(&steps("TERM:10,KILL")), 0);
+        assert_eq!(terminate_step(&steps("KILL")), 0);
+    }
+
+    #[test]
+    fn terminate_without_term_or_kill() {
+        assert_eq!(terminate_step(&steps("USR2:5,QUIT")), 0);
+    }
+}
@@ -0,0 +1,112 @@
+//  einhyrningsins: graceful restarts for socket-based daemons
+//  Copyright (C) 2016  Bryan Newbold <bnewbold@robocracy.org>
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU General Public License as published by
+//  the Free Software Foundation, either version 3 of the License, or
+//  (at your option) any later version.
+//
+//  This program is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//  GNU General Public License for more details.
+//
+//  You should have received a copy of the GNU General Public License
+//  along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+// Running in the background (--daemonize). The usual double fork, except that the process started
+// from the terminal hangs around until the daemon reports (over a pipe) whether it came up, and
+// exits accordingly; init scripts can trust its exit status.
+//
+// The daemon keeps the terminal's stdout and stderr until it is about to spawn children, so
+// errors during startup still show up there.
+
+use std::io;
+use std::io::prelude::*;
+use std::fs::{File, OpenOptions};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::process::exit;
+use libc;
+use nix;
+use nix::unistd::{fork, ForkResult};
+
+// The daemon's end of the pipe back to the foreground process
+pub struct Readiness {
+    fd: RawFd,
+}
+
+impl Readiness {
+    pub fn succeed(self) {
+        self.report(&format!("ok {}", nix::unistd::getpid()));
+    }
+
+    pub fn fail(self, msg: &str) {
+        self.report(msg);
+    }
+
+    fn report(self, msg: &str) {
+        let mut pipe = unsafe { File::from_raw_fd(self.fd) };
+        // If the foreground process is gone, there's nobody left to tell
+        pipe.write_all(msg.as_bytes()).ok();
+    }
+}
+
+// Forks into the background. Only returns in the daemon; the foreground process exits once the
+// daemon reports in, or dies trying.
+pub fn daemonize() -> Result<Readiness, String> {
+    // Close-on-exec, so no child ever holds the pipe open
+    let (read_fd, write_fd) = try!(nix::unistd::pipe2(nix::fcntl::O_CLOEXEC)
+        .map_err(|e| format!("Couldn't create pipe: {:?}", e)));
+    match try!(fork().map_err(|e| format!("Couldn't fork: {:?}", e))) {
+        ForkResult::Parent { child } => {
+            nix::unistd::close(write_fd).ok();
+            let mut report = String::new();
+            unsafe { File::from_raw_fd(read_fd) }.read_to_string(&mut report).ok();
+            nix::sys::wait::waitpid(child, None).ok();
+            if report.starts_with("ok ") {
+                println!("Started in the background (pid {})", &report[3..]);
+                exit(0);
+            }
+            if report.is_empty() {
+                println!("Failed to start");
+            } else {
+                println!("Failed to start: {}", report);
+            }
+            exit(1);
+        }
+        ForkResult::Child => (),
+    }
+    nix::unistd::close(read_fd).ok();
+
+    // A session of our own, then fork again so we can never re-acquire a controlling terminal
+    if unsafe { libc::setsid() } < 0 {
+        return Err(format!("Couldn't setsid: {}", io::Error::last_os_error()));
+    }
+    match try!(fork().map_err(|e| format!("Couldn't fork: {:?}", e))) {
+        ForkResult::Parent { .. } => unsafe { libc::_exit(0) },
+        ForkResult::Child => (),
+    }
+
+    let dev_null = try!(File::open("/dev/null")
+        .map_err(|e| format!("Couldn't open /dev/null: {}", e)));
+    try!(nix::unistd::dup2(dev_null.as_raw_fd(), 0)
+        .map_err(|e| format!("Couldn't redirect stdin: {:?}", e)));
+    Ok(Readiness { fd: write_fd })
+}
+
+// Points stdout and stderr (which children without --capture-output inherit) at the log file, or
+// /dev/null, letting go of the terminal
+pub fn detach_stdio(log_file: Option<&str>) -> Result<(), String> {
+    let path = log_file.unwrap_or("/dev/null");
+    let file = try!(OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(path)
+        .map_err(|e| format!("Couldn't open {}: {}", path, e)));
+    for fd in &[1, 2] {
+        try!(nix::unistd::dup2(file.as_raw_fd(), *fd)
+            .map_err(|e| format!("Couldn't redirect output to {}: {:?}", path, e)));
+    }
+    Ok(())
+}
@@ -0,0 +1,159 @@
+//  einhyrningsins: graceful restarts for socket-based daemons
+//  Copyright (C) 2016  Bryan Newbold <bnewbold@robocracy.org>
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU General Public License as published by
+//  the Free Software Foundation, either version 3 of the License, or
+//  (at your option) any later version.
+//
+//  This program is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//  GNU General Public License for more details.
+//
+//  You should have received a copy of the GNU General Public License
+//  along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+// Short-lived helper processes (probe commands, etc) are forked from the master just like the
+// offspring are, so the SIGCHLD `waitpid(-1)` loop in the main event loop can reap them out from
+// under whichever thread is waiting on them. Both sides go through this registry: whoever reaps a
+// helper first leaves the result here for the other.
+
+use std::io;
+use std::mem;
+use std::ptr;
+use std::thread;
+use std::process::{Command, Child};
+use std::os::unix::process::CommandExt;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use time::{Duration, SteadyTime};
+
+use libc;
+use nix;
+use nix::sys::wait::{waitpid, WaitStatus, WNOHANG};
+
+#[derive(Clone)]
+pub struct Helpers {
+    exits: Arc<Mutex<HashMap<u32, Option<WaitStatus>>>>,
+}
+
+impl Helpers {
+    pub fn new() -> Helpers {
+        Helpers { exits: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    // The lock is held across the fork so the SIGCHLD loop can't reap the helper before it has
+    // been registered.
+    pub fn spawn(&self, cmd: &mut Command) -> io::Result<Child> {
+        reset_signals(cmd);
+        let mut exits = self.exits.lock().unwrap();
+        let child = try!(cmd.spawn());
+        exits.insert(child.id(), None);
+        Ok(child)
+    }
+
+    // Blocks until the helper exits, killing it if it is still running after `timeout`. Returns
+    // the exit code.
+    pub fn wait(&self, pid: u32, timeout: Duration) -> Result<i32, String> {
+        let deadline = SteadyTime::now() + timeout;
+        let mut timed_out = false;
+        loop {
+            {
+                let mut exits = self.exits.lock().unwrap();
+                let status = match exits.get(&pid) {
+                    Some(&Some(status)) => Some(status),
+                    Some(&None) => {
+                        match waitpid(pid as i32, Some(WNOHANG)) {
+                            Ok(WaitStatus::StillAlive) => None,
+                            Ok(status) => Some(status),
+                            // Reaped by the SIGCHLD loop; the status will show up shortly
+                            Err(nix::Error::Sys(nix::Errno::ECHILD)) => None,
+                            Err(e) => {
+                                exits.remove(&pid);
+                                return Err(format!("waitpid error: {:?}", e));
+                            }
+                        }
+                    }
+                    None => return Err("not a registered helper".to_string()),
+                };
+                if let Some(status) = status {
+                    exits.remove(&pid);
+                    if timed_out {
+                        return Err("timed out".to_string());
+                    }
+                    return match status {
+                        WaitStatus::Exited(_, code) => Ok(code as i32),
+                        WaitStatus::Signaled(_, sig, _) => Err(format!("killed by {:?}", sig)),
+                        other => Err(format!("unexpected wait status: {:?}", other)),
+                    };
+                }
+            }
+            if !timed_out && SteadyTime::now() > deadline {
+                nix::sys::signal::kill(pid as i32, nix::sys::signal::Signal::SIGKILL).ok();
+                timed_out = true;
+            }
+            thread::sleep(::std::time::Duration::from_millis(50));
+        }
+    }
+
+    pub fn run(&self, cmd: &mut Command, timeout: Duration) -> Result<i32, String> {
+        let child = try!(self.spawn(cmd).map_err(|e| format!("couldn't spawn: {}", e)));
+        self.wait(child.id(), timeout)
+    }
+
+    pub fn is_helper(&self, pid: u32) -> bool {
+        self.exits.lock().unwrap().contains_key(&pid)
+    }
+
+    // Called from the SIGCHLD loop for any pid that isn't one of the offspring. Returns true if
+    // the pid belonged to a helper.
+    pub fn reaped(&self, pid: u32, status: WaitStatus) -> bool {
+        let mut exits = self.exits.lock().unwrap();
+        match exits.get_mut(&pid) {
+            Some(slot) => {
+                *slot = Some(status);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+// The signals main() asks chan_signal for (less STOP, which can't be caught or ignored)
+const HANDLED: &'static [libc::c_int] = &[libc::SIGHUP,
+                                          libc::SIGINT,
+                                          libc::SIGQUIT,
+                                          libc::SIGTERM,
+                                          libc::SIGPIPE,
+                                          libc::SIGALRM,
+                                          libc::SIGCHLD,
+                                          libc::SIGTTIN,
+                                          libc::SIGTTOU,
+                                          libc::SIGUSR1,
+                                          libc::SIGUSR2,
+                                          libc::SIGCONT];
+
+// The master blocks the signals it handles (see chan_signal::notify) rather than installing
+// handlers, so anything it forks would inherit that mask, and any of those signals the master was
+// started with ignored (as a non-interactive shell does INT and QUIT for background jobs): signals
+// sent to a child, including a PR_SET_PDEATHSIG one, would stay pending or be dropped. This
+// resets the lot in the child before it execs, as if the master had used ordinary handlers.
+pub fn reset_signals(cmd: &mut Command) {
+    unsafe {
+        cmd.before_exec(|| {
+            for sig in HANDLED {
+                if libc::signal(*sig, libc::SIG_DFL) == libc::SIG_ERR {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            let mut empty: libc::sigset_t = mem::zeroed();
+            libc::sigemptyset(&mut empty);
+            match libc::pthread_sigmask(libc::SIG_SETMASK, &empty, ptr::null_mut()) {
+                0 => Ok(()),
+                err => Err(io::Error::from_raw_os_error(err)),
+            }
+        });
+    }
+}
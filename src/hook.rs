@@ -0,0 +1,129 @@
+//  einhyrningsins: graceful restarts for socket-based daemons
+//  Copyright (C) 2016  Bryan Newbold <bnewbold@robocracy.org>
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU General Public License as published by
+//  the Free Software Foundation, either version 3 of the License, or
+//  (at your option) any later version.
+//
+//  This program is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//  GNU General Public License for more details.
+//
+//  You should have received a copy of the GNU General Public License
+//  along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+// Lifecycle hooks: shell commands run by the master at key points, with details about the event
+// in their environment. Their output ends up in the master's log.
+
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::thread;
+use std::process::{Command, Stdio};
+use time::Duration;
+use slog;
+
+use helper::Helpers;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum HookEvent {
+    Start,
+    PreUpgrade,
+    PostUpgrade,
+    Crash,
+    RetriesExhausted,
+    Exit,
+}
+
+impl HookEvent {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            HookEvent::Start => "start",
+            HookEvent::PreUpgrade => "pre-upgrade",
+            HookEvent::PostUpgrade => "post-upgrade",
+            HookEvent::Crash => "crash",
+            HookEvent::RetriesExhausted => "retries-exhausted",
+            HookEvent::Exit => "exit",
+        }
+    }
+
+    // Parses specs like "crash:/usr/local/bin/page-someone"
+    pub fn parse(spec: &str) -> Result<(HookEvent, String), String> {
+        let mut chunks = spec.splitn(2, ':');
+        let name = chunks.next().unwrap(); // safe
+        let event = match name {
+            "start" => HookEvent::Start,
+            "pre-upgrade" => HookEvent::PreUpgrade,
+            "post-upgrade" => HookEvent::PostUpgrade,
+            "crash" => HookEvent::Crash,
+            "retries-exhausted" => HookEvent::RetriesExhausted,
+            "exit" => HookEvent::Exit,
+            _ => {
+                return Err(format!("Unknown hook event '{}'; expected start, pre-upgrade, \
+                                    post-upgrade, crash, retries-exhausted or exit",
+                                   name))
+            }
+        };
+        match chunks.next() {
+            Some(cmd) if !cmd.is_empty() => Ok((event, cmd.to_string())),
+            _ => Err(format!("Missing command for '{}' hook; expected EVENT:CMD", name)),
+        }
+    }
+}
+
+// Runs one hook command through /bin/sh, blocking until it exits (or is killed after `timeout`).
+// Each line of output is logged as it comes in.
+pub fn run(event: HookEvent,
+           cmdline: &str,
+           env: &[(String, String)],
+           timeout: Duration,
+           helpers: &Helpers,
+           log: &slog::Logger) {
+    let log = log.new(o!("hook" => event.name()));
+    let mut cmd = Command::new("/bin/sh");
+    cmd.arg("-c")
+        .arg(cmdline)
+        .env("EINHORN_HOOK", event.name())
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    for &(ref var, ref value) in env {
+        cmd.env(var, value);
+    }
+    let mut child = match helpers.spawn(&mut cmd) {
+        Ok(child) => child,
+        Err(e) => {
+            warn!(log, "couldn't run hook"; "err" => e.to_string());
+            return;
+        }
+    };
+    debug!(log, "running hook"; "cmd" => cmdline);
+
+    // Separate threads, so a chatty hook can't block on a full pipe. They aren't waited for: a
+    // background process started by the hook could hold the pipes open indefinitely.
+    if let Some(out) = child.stdout.take() {
+        log_lines(out, "stdout", log.clone());
+    }
+    if let Some(err) = child.stderr.take() {
+        log_lines(err, "stderr", log.clone());
+    }
+
+    match helpers.wait(child.id(), timeout) {
+        Ok(0) => debug!(log, "hook succeeded"),
+        Ok(code) => warn!(log, "hook failed"; "status" => code),
+        Err(e) => warn!(log, "hook failed"; "err" => e),
+    }
+}
+
+fn log_lines<R: Read + Send + 'static>(stream: R, name: &'static str, log: slog::Logger) {
+    thread::spawn(move || {
+        for line in BufReader::new(stream).lines() {
+            match line {
+                Ok(line) => info!(log, "hook output"; "stream" => name, "line" => line),
+                Err(_) => break,
+            }
+        }
+    });
+}
@@ -19,11 +19,14 @@
 extern crate chan;
 #[macro_use]
 extern crate slog;
+extern crate slog_stream;
 extern crate slog_syslog;
 extern crate slog_term;
+#[macro_use]
 extern crate json;
 extern crate getopts;
 extern crate nix;
+extern crate libc;
 extern crate timer;
 extern crate time;
 extern crate chan_signal;
@@ -33,17 +36,17 @@ use std::io::{BufReader, BufWriter};
 use std::env;
 use std::fs;
 use std::u64;
-use std::str::FromStr;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::exit;
-use std::process::Command;
-use std::process::Child;
+use std::process::{Command, Stdio};
+use std::os::unix::process::CommandExt;
+use std::os::unix::fs::PermissionsExt;
+use std::sync::Arc;
 use std::net::SocketAddr;
-use std::net::TcpListener;
-use std::net::ToSocketAddrs;
 use std::os::unix::net::{UnixStream, UnixListener};
 use std::thread;
-use std::os::unix::io::{RawFd, IntoRawFd};
+use std::os::unix::io::{RawFd, IntoRawFd, AsRawFd, FromRawFd};
+use std::ffi::CString;
 use time::Duration;
 use std::collections::HashMap;
 use getopts::Options;
@@ -52,40 +55,116 @@ use chan_signal::Signal;
 use chan::{Sender, Receiver};
 use slog::DrainExt;
 
+mod bind;
+mod config;
+mod daemon;
+mod helper;
+mod hook;
+mod output;
+mod pidfd;
+mod pidfile;
+mod probe;
+mod procfs;
+mod template;
 
-#[derive(Clone, Debug, PartialEq)]
-struct EinConfig {
-    program: String,
-    program_args: Vec<String>,
-    count: u64,
-    childhood: Duration,
-    graceperiod: Duration,
-    retries: u64,
-    ipv4_only: bool,
-    ipv6_only: bool,
-    manual_ack: bool,
-    ctrl_path: String,
-    bind_slugs: Vec<String>,
-    env_drops: Vec<String>,
-    verbose: bool,
-    syslog: bool,
+use config::{EinConfig, PoolConfig, ein_options, signal_from_str};
+use helper::Helpers;
+use hook::HookEvent;
+use pidfile::PidFile;
+
+struct Pool {
+    cfg: PoolConfig,
+    generation: u64,
+    // Whether the current generation is still replacing the last one
+    upgrading: bool,
+    // Of the TCP sockets, for probes
+    bind_addrs: Vec<SocketAddr>,
+    bind_fds: Vec<RawFd>,
+    // Where captured output goes, if not the log
+    output: Option<Arc<output::OutputFile>>,
+    log: slog::Logger,
 }
 
 struct EinState {
-    cmd: Command,
     ctrl_req_rx: Receiver<CtrlRequest>,
     cfg: EinConfig,
     timer: timer::Timer,
     timer_tx: Sender<TimerAction>,
     timer_rx: Receiver<TimerAction>,
+    probe_tx: Sender<ProbeResult>,
+    probe_rx: Receiver<ProbeResult>,
+    helpers: Helpers,
+    pools: Vec<Pool>,
+    ctrl_fd: RawFd,
+    draining: Option<timer::Guard>,
+    sampler: Option<timer::Guard>,
+    log_file: Option<Arc<output::OutputFile>>,
+    pid_file: Option<PidFile>,
+    // Until the initial children are up, when daemonizing
+    readiness: Option<daemon::Readiness>,
+    // Our own program, as an absolute path if argv[0] was a relative one
+    exe: Option<String>,
+    // Files of unix sockets dropped by a reload, removed once no child listens on them anymore
+    dropped_socks: Vec<PathBuf>,
     log: slog::Logger,
 }
 
+impl EinState {
+    pub fn pool(&self, name: &str) -> &Pool {
+        self.pools.iter().find(|p| p.cfg.name == name).expect("unknown pool")
+    }
+
+    pub fn pool_mut(&mut self, name: &str) -> &mut Pool {
+        self.pools.iter_mut().find(|p| p.cfg.name == name).expect("unknown pool")
+    }
+
+    // The pool a control command applies to: the one named, or the only one there is
+    pub fn target_pool(&self, name: &Option<String>) -> Result<String, String> {
+        match *name {
+            Some(ref name) => {
+                if self.pools.iter().any(|p| p.cfg.name == *name) {
+                    Ok(name.clone())
+                } else {
+                    Err(format!("Unknown pool '{}'", name))
+                }
+            }
+            None if self.pools.len() == 1 => Ok(self.pools[0].cfg.name.clone()),
+            None => Err(format!("Which pool? One of: {}", self.pool_names().join(", "))),
+        }
+    }
+
+    // Like target_pool(), but no name means every pool
+    pub fn target_pools(&self, name: &Option<String>) -> Result<Vec<String>, String> {
+        match *name {
+            Some(_) => self.target_pool(name).map(|p| vec![p]),
+            None => Ok(self.pool_names()),
+        }
+    }
+
+    pub fn pool_names(&self) -> Vec<String> {
+        self.pools.iter().map(|p| p.cfg.name.clone()).collect()
+    }
+}
+
 #[derive(Copy, Clone, Debug, PartialEq)]
 enum TimerAction {
     CheckAlive(u32),
-    CheckTerminated(u32),
-    CheckShutdown(u32),
+    CheckShutdown(u32, usize),
+    Probe(u32),
+    CheckWatchdog(u32),
+    SampleResources,
+    CheckAge(u32),
+    DrainTimeout,
+    ReExec,
+    CheckForeign,
+    // Pre-upgrade hooks are done: pool index, generation, and whether an upgrade was underway
+    Upgrade(usize, u64, bool),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct ProbeResult {
+    pid: u32,
+    result: Result<(), String>,
 }
 
 #[derive(Copy, Clone, Debug, PartialEq)]
@@ -93,15 +172,20 @@ enum CtrlAction {
     Increment,
     Decrement,
     ManualAck(u32),
+    Heartbeat(u32),
     SigAll(Signal),
     ShutdownAll,
     UpgradeAll,
+    Reload,
+    ReopenLogs,
     Status,
+    ReExec,
 }
 
 #[derive(Clone, Debug, PartialEq)]
 struct CtrlRequest {
     action: CtrlAction,
+    pool: Option<String>,
     tx: Sender<String>,
 }
 
@@ -115,39 +199,303 @@ enum OffspringState {
 
 struct Offspring {
     state: OffspringState,
-    process: Child,
+    pool: String,
+    pid: u32,
+    slot: usize,
+    generation: u64,
     attempts: u64,
     timer_guard: Option<timer::Guard>,
+    probe_guard: Option<timer::Guard>,
+    probe_failures: u64,
+    watchdog_guard: Option<timer::Guard>,
+    age_guard: Option<timer::Guard>,
+    born: time::Timespec,
+    shutdown_step: Option<usize>,
     replaces: Option<u32>,
+    output: Vec<output::Capture>,
+    // Whether the child leads its own process group, and if so, which signals go to all of it
+    process_group: bool,
+    signal_group: bool,
+    // Start time from procfs, which tells the child apart from later processes with the same pid
+    starttime: Option<u64>,
+    // Adopted from a crashed master: not our child, so we poll for its exit instead of waiting
+    foreign: bool,
     log: slog::Logger,
 }
 
 impl Offspring {
-    pub fn spawn(state: &mut EinState) -> Result<Offspring, String> {
+    pub fn spawn(state: &mut EinState, pool: &str, slot: usize) -> Result<Offspring, String> {
+        let mut cmd = try!(child_command(state.pool(pool), &state.cfg.ctrl_path, slot));
+        let mut child = try!(cmd.spawn().map_err(|e| {
+            format!("Couldn't spawn '{}': {}", state.pool(pool).cfg.program, e)
+        }));
         let mut o = Offspring {
             state: OffspringState::Infancy,
-            process: state.cmd.spawn().expect("error spawning"),
+            pool: pool.to_string(),
+            pid: child.id(),
+            slot: slot,
+            generation: state.pool(pool).generation,
             attempts: 0,
             timer_guard: None,
+            probe_guard: None,
+            probe_failures: 0,
+            watchdog_guard: None,
+            age_guard: None,
+            born: time::get_time(),
+            shutdown_step: None,
             replaces: None,
+            output: vec![],
+            process_group: state.pool(pool).cfg.process_group,
+            signal_group: state.pool(pool).cfg.signal_group,
+            starttime: None,
+            foreign: false,
             log: state.log.clone(),
         };
-        let pid = o.process.id();
-        o.log = state.log.new(o!("child_pid" => pid,));
+        let pid = o.pid;
+        o.starttime = procfs::stat(pid).ok().map(|s| s.starttime);
+        if o.process_group {
+            // The child does this itself too, but it may not have got that far yet
+            nix::unistd::setpgid(pid as i32, pid as i32).ok();
+        }
+        o.log = state.pool(pool).log.new(o!("child_pid" => pid, "slot" => slot));
+        if let Some(out) = child.stdout.take() {
+            o.capture(state, out.into_raw_fd(), "stdout");
+        }
+        if let Some(err) = child.stderr.take() {
+            o.capture(state, err.into_raw_fd(), "stderr");
+        }
+        o.schedule_check_alive(state);
+        info!(o.log, "spawned");
+        Ok(o)
+    }
+
+    // Picks up a child spawned by our predecessor (before a re-exec, or before it crashed) and
+    // restarts its timers
+    pub fn adopt(state: &mut EinState,
+                 c: &json::JsonValue,
+                 crashed: bool)
+                 -> Result<Offspring, String> {
+        let pid = try!(c["pid"].as_u32().ok_or("missing pid".to_string()));
+        let o_state = match c["state"].as_str() {
+            Some("infancy") => OffspringState::Infancy,
+            Some("healthy") => OffspringState::Healthy,
+            Some("notified") => OffspringState::Notified,
+            Some("dead") => OffspringState::Dead,
+            _ => return Err(format!("unknown state for child {}", pid)),
+        };
+        let pool = match c["pool"].as_str() {
+            Some(name) if state.pools.iter().any(|p| p.cfg.name == name) => name.to_string(),
+            _ => return Err(format!("unknown pool for child {}", pid)),
+        };
+        let slot = c["slot"].as_usize().unwrap_or(0);
+        let mut o = Offspring {
+            state: o_state,
+            pid: pid,
+            slot: slot,
+            generation: c["generation"].as_u64().unwrap_or(0),
+            attempts: c["attempts"].as_u64().unwrap_or(0),
+            timer_guard: None,
+            probe_guard: None,
+            probe_failures: 0,
+            watchdog_guard: None,
+            age_guard: None,
+            born: c["born"].as_i64().map(|s| time::Timespec::new(s, 0)).unwrap_or(time::get_time()),
+            shutdown_step: c["shutdown_step"].as_usize(),
+            replaces: c["replaces"].as_u32(),
+            output: vec![],
+            process_group: c["process_group"].as_bool().unwrap_or(false),
+            signal_group: c["signal_group"].as_bool().unwrap_or(false),
+            starttime: c["starttime"].as_u64(),
+            foreign: crashed || c["foreign"].as_bool().unwrap_or(false),
+            log: state.pool(&pool).log.new(o!("child_pid" => pid, "slot" => slot)),
+            pool: pool,
+        };
+        // Output pipes died with a crashed master
+        let output = if crashed { &json::JsonValue::Null } else { &c["output"] };
+        for out in output.members() {
+            let stream = match out["stream"].as_str() {
+                Some("stdout") => "stdout",
+                Some("stderr") => "stderr",
+                _ => continue,
+            };
+            if let Some(fd) = out["fd"].as_i32() {
+                set_cloexec(fd, true).ok();
+                o.capture(state, fd, stream);
+            }
+        }
+        match o.state {
+            OffspringState::Infancy => o.schedule_check_alive(state),
+            OffspringState::Healthy => {
+                o.schedule_probe(state);
+                o.feed_watchdog(state);
+                o.schedule_retirement(state);
+            }
+            OffspringState::Notified => {
+                // Pick the shutdown sequence back up where it left off
+                let step = o.shutdown_step.unwrap_or(0);
+                let wait = {
+                    let cfg = &state.pool(&o.pool).cfg;
+                    cfg.shutdown_steps.get(step).map(|&(_, wait)| wait).unwrap_or(cfg.graceperiod)
+                };
+                let t_tx = state.timer_tx.clone();
+                o.timer_guard = Some(state.timer.schedule_with_delay(wait, move || {
+                    t_tx.send(TimerAction::CheckShutdown(pid, step + 1));
+                }));
+            }
+            OffspringState::Dead => (),
+        }
+        info!(o.log, "adopted"; "state" => format!("{:?}", o.state), "foreign" => o.foreign);
+        Ok(o)
+    }
+
+    pub fn to_json(&self) -> json::JsonValue {
+        object!{
+            "pid" => self.pid,
+            "pool" => self.pool.clone(),
+            "slot" => self.slot,
+            "generation" => self.generation,
+            "state" => self.state_str(),
+            "attempts" => self.attempts,
+            "born" => self.born.sec,
+            "shutdown_step" => self.shutdown_step,
+            "replaces" => self.replaces,
+            "process_group" => self.process_group,
+            "signal_group" => self.signal_group,
+            "starttime" => self.starttime,
+            "foreign" => self.foreign,
+            "output" => self.open_output()
+                .map(|c| object!{ "fd" => c.fd, "stream" => c.stream })
+                .collect::<Vec<json::JsonValue>>()
+        }
+    }
+
+    // Starts passing along what the child writes to the pipe `fd`
+    fn capture(&mut self, state: &EinState, fd: RawFd, stream: &'static str) {
+        let pool = state.pool(&self.pool);
+        let tag = format!("[{} slot {} pid {}]", pool.cfg.name, self.slot, self.pid);
+        self.output.push(output::capture(fd, stream, pool.output.clone(), tag, self.log.clone()));
+    }
+
+    // Captured output pipes still being read
+    pub fn open_output<'a>(&'a self) -> Box<Iterator<Item = &'a output::Capture> + 'a> {
+        Box::new(self.output.iter().filter(|c| c.is_open()))
+    }
+
+    pub fn state_str(&self) -> &'static str {
+        match self.state {
+            OffspringState::Infancy => "infancy",
+            OffspringState::Healthy => "healthy",
+            OffspringState::Notified => "notified",
+            OffspringState::Dead => "dead",
+        }
+    }
+
+    // Whether this child is (or is about to be) filling its slot, as opposed to on its way out
+    pub fn holds_slot(&self) -> bool {
+        self.state == OffspringState::Infancy || self.state == OffspringState::Healthy
+    }
+
+    // The configuration of the pool this child belongs to
+    pub fn cfg<'a>(&self, state: &'a EinState) -> &'a PoolConfig {
+        &state.pool(&self.pool).cfg
+    }
+
+    fn schedule_check_alive(&mut self, state: &mut EinState) {
+        let pid = self.pid;
         let t_tx = state.timer_tx.clone();
-        o.timer_guard = Some(state.timer.schedule_with_delay(state.cfg.childhood, move || {
+        self.timer_guard = Some(state.timer.schedule_with_delay(self.cfg(state).childhood, move || {
             t_tx.send(TimerAction::CheckAlive(pid));
         }));
-        info!(o.log, "spawned");
-        Ok(o)
     }
 
     pub fn respawn(&mut self, state: &mut EinState) -> Result<Offspring, String> {
-        let mut successor = try!(Offspring::spawn(state));
-        successor.replaces = Some(self.process.id());
+        let mut successor = try!(Offspring::spawn(state, &self.pool, self.slot));
+        successor.replaces = Some(self.pid);
         Ok(successor)
     }
 
+    pub fn schedule_probe(&mut self, state: &mut EinState) {
+        if self.cfg(state).probe.is_none() || !self.is_active() {
+            return;
+        }
+        let pid = self.pid;
+        let t_tx = state.timer_tx.clone();
+        let interval = self.cfg(state).probe_interval;
+        self.probe_guard = Some(state.timer.schedule_with_delay(interval, move || {
+            t_tx.send(TimerAction::Probe(pid));
+        }));
+    }
+
+    // (Re)arms the heartbeat watchdog; replacing the guard cancels the previous deadline
+    pub fn feed_watchdog(&mut self, state: &mut EinState) {
+        let interval = match self.cfg(state).watchdog {
+            Some(interval) => interval,
+            None => return,
+        };
+        let pid = self.pid;
+        let t_tx = state.timer_tx.clone();
+        self.watchdog_guard = Some(state.timer.schedule_with_delay(interval, move || {
+            t_tx.send(TimerAction::CheckWatchdog(pid));
+        }));
+    }
+
+    // Schedules replacement once the child reaches the maximum age, plus some jitter so children
+    // spawned together don't all get recycled together
+    pub fn schedule_retirement(&mut self, state: &mut EinState) {
+        let max_age = match self.cfg(state).max_age {
+            Some(max_age) => max_age,
+            None => return,
+        };
+        // No need for anything cryptographic here
+        let jitter_ms = self.cfg(state).max_age_jitter.num_milliseconds() as u64;
+        let jitter = if jitter_ms > 0 {
+            let seed = time::precise_time_ns() ^ ((self.pid as u64) << 16);
+            Duration::milliseconds((seed % jitter_ms) as i64)
+        } else {
+            Duration::zero()
+        };
+        let delay = max_age + jitter - (time::get_time() - self.born);
+        self.schedule_age_check(state, if delay < Duration::zero() {
+            Duration::zero()
+        } else {
+            delay
+        });
+    }
+
+    fn schedule_age_check(&mut self, state: &mut EinState, delay: Duration) {
+        let pid = self.pid;
+        let t_tx = state.timer_tx.clone();
+        self.age_guard = Some(state.timer.schedule_with_delay(delay, move || {
+            t_tx.send(TimerAction::CheckAge(pid));
+        }));
+    }
+
+    // Runs the probe in its own thread; the result comes back to the event loop as a ProbeResult
+    pub fn probe(&mut self, state: &mut EinState) {
+        let vars = template::Vars {
+            slot: self.slot,
+            generation: self.generation,
+            master_pid: nix::unistd::getpid() as u32,
+        };
+        let probe = match self.cfg(state).probe {
+            Some(ref p) => p.clone(),
+            None => return,
+        };
+        let pid = self.pid;
+        let default_addr = state.pool(&self.pool).bind_addrs.first().cloned();
+        let timeout = self.cfg(state).probe_timeout;
+        let helpers = state.helpers.clone();
+        let p_tx = state.probe_tx.clone();
+        thread::spawn(move || {
+            let result = probe.expand(&vars)
+                .and_then(|probe| probe.check(pid, default_addr, timeout, &helpers));
+            p_tx.send(ProbeResult {
+                pid: pid,
+                result: result,
+            });
+        });
+    }
+
     pub fn is_active(&self) -> bool {
         match self.state {
             OffspringState::Infancy | OffspringState::Healthy | OffspringState::Notified => true,
@@ -163,29 +511,45 @@ impl Offspring {
         self.state = OffspringState::Dead;
     }
 
+    // Like shutdown(), but skips any graceful steps before the first TERM (or KILL)
     pub fn terminate(&mut self, state: &mut EinState) {
-        if !self.is_active() {
-            return;
-        }
-        self.signal(Signal::TERM);
-        self.state = OffspringState::Notified;
-        let pid = self.process.id();
-        let t_tx = state.timer_tx.clone();
-        self.timer_guard = Some(state.timer.schedule_with_delay(state.cfg.graceperiod, move || {
-            t_tx.send(TimerAction::CheckTerminated(pid));
-        }));
+        let step = config::terminate_step(&self.cfg(state).shutdown_steps);
+        self.escalate(state, step);
     }
 
     pub fn shutdown(&mut self, state: &mut EinState) {
+        self.escalate(state, 0);
+    }
+
+    // Sends the given step of the shutdown sequence, then schedules a check which moves on to the
+    // next step if the child is still around once this step's wait is up. Never goes backwards.
+    pub fn escalate(&mut self, state: &mut EinState, step: usize) {
         if !self.is_active() {
             return;
         }
-        self.signal(Signal::USR2);
+        if let Some(current) = self.shutdown_step {
+            if current >= step {
+                return;
+            }
+        }
+        let (sig, wait) = match self.cfg(state).shutdown_steps.get(step) {
+            Some(&s) => s,
+            None => {
+                warn!(self.log, "still alive after last shutdown step");
+                return;
+            }
+        };
+        self.shutdown_step = Some(step);
+        if sig == Signal::KILL {
+            self.kill();
+            return;
+        }
+        self.signal(sig);
         self.state = OffspringState::Notified;
-        let pid = self.process.id();
+        let pid = self.pid;
         let t_tx = state.timer_tx.clone();
-        self.timer_guard = Some(state.timer.schedule_with_delay(state.cfg.graceperiod, move || {
-            t_tx.send(TimerAction::CheckShutdown(pid));
+        self.timer_guard = Some(state.timer.schedule_with_delay(wait, move || {
+            t_tx.send(TimerAction::CheckShutdown(pid, step + 1));
         }));
     }
 
@@ -193,170 +557,500 @@ impl Offspring {
         if self.state == OffspringState::Dead {
             return;
         }
-        let nix_sig = match sig {
-            Signal::HUP   => nix::sys::signal::Signal::SIGHUP,
-            Signal::INT   => nix::sys::signal::Signal::SIGINT,
-            Signal::QUIT  => nix::sys::signal::Signal::SIGQUIT,
-            Signal::TERM  => nix::sys::signal::Signal::SIGTERM,
-            Signal::KILL  => nix::sys::signal::Signal::SIGKILL,
-            Signal::TTIN  => nix::sys::signal::Signal::SIGTTIN,
-            Signal::TTOU  => nix::sys::signal::Signal::SIGTTOU,
-            Signal::USR1  => nix::sys::signal::Signal::SIGUSR1,
-            Signal::USR2  => nix::sys::signal::Signal::SIGUSR2,
-            Signal::STOP  => nix::sys::signal::Signal::SIGSTOP,
-            Signal::CONT  => nix::sys::signal::Signal::SIGCONT,
-            _ => { 
+        let nix_sig = match nix_signal(sig) {
+            Some(nix_sig) => nix_sig,
+            None => {
                 warn!(self.log, "tried to send unexpected signal";
                     "signal" => format!("{:?}", sig));
                 return;
             }
         };
-        nix::sys::signal::kill(self.process.id() as i32, nix_sig).unwrap();
+        if let Err(e) = nix::sys::signal::kill(self.signal_target(sig), nix_sig) {
+            warn!(self.log, "couldn't send signal";
+                "signal" => format!("{:?}", sig), "err" => format!("{:?}", e));
+        }
+    }
+
+    // Where a signal goes: just the child, or its whole process group (a negative pid)
+    pub fn signal_target(&self, sig: Signal) -> i32 {
+        if self.signal_group || (self.process_group && sig == Signal::KILL) {
+            -(self.pid as i32)
+        } else {
+            self.pid as i32
+        }
+    }
+}
+
+// The nix equivalent of a signal, for the ones we send to children
+fn nix_signal(sig: Signal) -> Option<nix::sys::signal::Signal> {
+    match sig {
+        Signal::HUP   => Some(nix::sys::signal::Signal::SIGHUP),
+        Signal::INT   => Some(nix::sys::signal::Signal::SIGINT),
+        Signal::QUIT  => Some(nix::sys::signal::Signal::SIGQUIT),
+        Signal::TERM  => Some(nix::sys::signal::Signal::SIGTERM),
+        Signal::KILL  => Some(nix::sys::signal::Signal::SIGKILL),
+        Signal::TTIN  => Some(nix::sys::signal::Signal::SIGTTIN),
+        Signal::TTOU  => Some(nix::sys::signal::Signal::SIGTTOU),
+        Signal::USR1  => Some(nix::sys::signal::Signal::SIGUSR1),
+        Signal::USR2  => Some(nix::sys::signal::Signal::SIGUSR2),
+        Signal::STOP  => Some(nix::sys::signal::Signal::SIGSTOP),
+        Signal::CONT  => Some(nix::sys::signal::Signal::SIGCONT),
+        _ => None,
+    }
+}
+
+// Builds the command for the child in `slot` of `pool`, with any placeholders in its arguments
+// and environment filled in
+fn child_command(pool: &Pool, ctrl_path: &str, slot: usize) -> Result<Command, String> {
+    let vars = template::Vars {
+        slot: slot,
+        generation: pool.generation,
+        master_pid: nix::unistd::getpid() as u32,
+    };
+    let mut cmd = Command::new(pool.cfg.program.clone());
+    for arg in &pool.cfg.program_args {
+        cmd.arg(try!(template::expand(arg, &vars)));
+    }
+    for var in &pool.cfg.env_drops {
+        cmd.env_remove(var);
+    }
+    for &(ref var, ref value) in &pool.cfg.env_sets {
+        cmd.env(var, try!(template::expand(value, &vars)));
+    }
+
+    cmd.env("EINHORN_FD_COUNT", pool.bind_fds.len().to_string());
+    for (i, fd) in pool.bind_fds.iter().enumerate() {
+        cmd.env(format!("EINHORN_FD_{}", i), fd.to_string());
+        if let Some(addr) = bind::bound_addr(*fd) {
+            cmd.env(format!("EINHORN_FD_TYPE_{}", i), addr.kind());
+        }
+    }
+    cmd.env("EINHORN_SOCK_PATH", ctrl_path);
+    cmd.env("EINHORN_POOL_NAME", pool.cfg.name.clone());
+    cmd.env("EINHORN_CHILD_INDEX", slot.to_string());
+    cmd.env("EINHORN_GENERATION", pool.generation.to_string());
+    if pool.cfg.capture_output {
+        cmd.stdout(Stdio::piped());
+        cmd.stderr(Stdio::piped());
+    }
+    // First, so a pdeathsig raised below is delivered right away
+    helper::reset_signals(&mut cmd);
+    if pool.cfg.process_group {
+        unsafe {
+            cmd.before_exec(|| {
+                nix::unistd::setpgid(0, 0).map_err(|_| std::io::Error::last_os_error())
+            });
+        }
+    }
+    if let Some(sig) = pool.cfg.pdeathsig.and_then(nix_signal) {
+        let master_pid = nix::unistd::getpid();
+        unsafe {
+            cmd.before_exec(move || {
+                if libc::prctl(libc::PR_SET_PDEATHSIG, sig as libc::c_ulong) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                // The master may have died before the signal was set up
+                if nix::unistd::getppid() != master_pid {
+                    libc::raise(sig as libc::c_int);
+                }
+                Ok(())
+            });
+        }
     }
+    Ok(cmd)
 }
 
 // * * * * * * *   Main Event Loop   * * * * * * *
-fn shepard(mut state: EinState, signal_rx: Receiver<Signal>) {
+fn shepard(mut state: EinState,
+           signal_rx: Receiver<Signal>,
+           resumed: Option<json::JsonValue>,
+           crashed: Option<json::JsonValue>)
+           -> i32 {
 
-    /// birth the initial set of offspring
+    /// birth the initial set of offspring (or adopt our predecessor's)
     let mut brood: HashMap<u32, Offspring> = HashMap::new();
-    for _ in 0..state.cfg.count {
-        let o = Offspring::spawn(&mut state).unwrap();
-        let pid = o.process.id();
-        brood.insert(pid, o);
+    match resumed {
+        Some(r) => {
+            for c in r["children"].members() {
+                match Offspring::adopt(&mut state, c, false) {
+                    Ok(o) => {
+                        brood.insert(o.pid, o);
+                    }
+                    Err(e) => {
+                        warn!(state.log, "couldn't adopt child"; "err" => e);
+                    }
+                }
+            }
+        }
+        None => {
+            run_hooks(&state, HookEvent::Start, vec![]);
+            // Whatever a crashed master left running gets taken over, then replaced with our own
+            // spawns
+            if let Some(c) = crashed {
+                for child in c["children"].members() {
+                    match Offspring::adopt(&mut state, child, true) {
+                        Ok(o) => {
+                            brood.insert(o.pid, o);
+                        }
+                        Err(e) => {
+                            warn!(state.log, "couldn't adopt child"; "err" => e);
+                        }
+                    }
+                }
+            }
+            let survivors: Vec<u32> =
+                brood.values().filter(|o| o.holds_slot()).map(|o| o.pid).collect();
+            for pid in survivors {
+                offspring_replace(&mut brood, &mut state, pid, "adopted from crashed master");
+            }
+            for name in state.pool_names() {
+                fill_slots(&mut brood, &mut state, &name);
+            }
+        }
     }
 
+    schedule_sampling(&mut state);
+
+    // Children adopted from a crashed master aren't ours to wait for, so check on them instead
+    let mut foreign_poll = if brood.values().any(|o| o.foreign) {
+        let t_tx = state.timer_tx.clone();
+        Some(state.timer.schedule_repeating(Duration::seconds(1), move || {
+            t_tx.send(TimerAction::CheckForeign);
+        }))
+    } else {
+        None
+    };
+
     // Ugh, see: http://burntsushi.net/rustdoc/chan/macro.chan_select.html#failure-modes
     let ctrl_req_rx = state.ctrl_req_rx.clone();
     let timer_rx = state.timer_rx.clone();
+    let probe_rx = state.probe_rx.clone();
 
     /// infinite select() loop over timers, signals
     let mut run = true;
+    let mut exit_code = 0;
+    // How the most recent child went, for --init's exit status
+    let mut last_exit = None;
+    // What was last written to the state file
+    let mut last_saved = String::new();
     loop {
+        check_ready(&mut brood, &mut state);
+        save_state(&brood, &state, &mut last_saved);
         chan_select! {
             timer_rx.recv() -> action => match action.expect("Error with timer thread") {
                 TimerAction::CheckAlive(pid) => {
-                    // Need to move 'o' out of HashMap here so we can mutate
-                    // the map in other ways
-                    if let Some(mut o) = brood.remove(&pid) {
-                        if !state.cfg.manual_ack && o.state == OffspringState::Infancy {
-                            if o.is_active() {
-                                debug!(o.log, "found to be alive");
-                                o.state = OffspringState::Healthy;
-                                if let Some(old_pid) = o.replaces {
-                                    if let Some(old) = brood.get_mut(&old_pid) {
-                                        old.shutdown(&mut state);
-                                    }
-                                }
-                            }
-                        } else if state.cfg.manual_ack && o.state == OffspringState::Infancy {
-                            warn!(o.log, "didn't ack in time, not healthy";
-                                "max_retries" => state.cfg.retries,
-                                "attempts" => o.attempts);
-                            if o.attempts + 1 >= state.cfg.retries {
-                                warn!(o.log, "ran out of retries");
-                            } else {
-                                let mut successor = o.respawn(&mut state).unwrap();
-                                successor.attempts = o.attempts + 1;
-                                brood.insert(successor.process.id(), successor);
-                            }
-                            o.terminate(&mut state);
-                        } else {
-                            warn!(o.log, "Unexpected CheckAlive state!";
-                                "state" => format!("{:?}", o.state));
+                    let (o_state, manual_ack, probed) = match brood.get(&pid) {
+                        Some(o) => {
+                            let cfg = o.cfg(&state);
+                            (Some(o.state), cfg.manual_ack, cfg.probe.is_some())
                         }
-                        brood.insert(pid, o);
+                        None => (None, false, false),
                     };
+                    match o_state {
+                        Some(OffspringState::Infancy) if manual_ack => {
+                            {
+                                let o = &brood[&pid];
+                                warn!(o.log, "didn't ack in time, not healthy";
+                                    "max_retries" => o.cfg(&state).retries,
+                                    "attempts" => o.attempts);
+                            }
+                            offspring_unhealthy(&mut brood, &mut state, pid);
+                        },
+                        Some(OffspringState::Infancy) if probed => {
+                            // Alive, but not healthy until the readiness probe passes
+                            let o = brood.get_mut(&pid).unwrap();
+                            debug!(o.log, "found to be alive, probing");
+                            o.probe(&mut state);
+                        },
+                        Some(OffspringState::Infancy) => {
+                            debug!(brood[&pid].log, "found to be alive");
+                            offspring_healthy(&mut brood, &mut state, pid);
+                        },
+                        Some(other) => {
+                            warn!(brood[&pid].log, "Unexpected CheckAlive state!";
+                                "state" => format!("{:?}", other));
+                        },
+                        None => (),
+                    }
                 },
-                TimerAction::CheckShutdown(pid) => {
+                TimerAction::CheckShutdown(pid, step) => {
                     if let Some(o) = brood.get_mut(&pid) {
                         if o.is_active() {
-                            o.terminate(&mut state);
+                            o.escalate(&mut state, step);
                         }
                     }
                 },
-                TimerAction::CheckTerminated(pid) => {
+                TimerAction::Probe(pid) => {
                     if let Some(o) = brood.get_mut(&pid) {
-                        if o.is_active() {
-                            o.kill();
+                        if o.holds_slot() {
+                            o.probe(&mut state);
+                        }
+                    }
+                },
+                TimerAction::CheckWatchdog(pid) => {
+                    if brood.get(&pid).map(|o| o.state) == Some(OffspringState::Healthy) {
+                        {
+                            let o = brood.get_mut(&pid).unwrap();
+                            warn!(o.log, "missed heartbeat";
+                                "watchdog_secs" => o.cfg(&state).watchdog.unwrap().num_seconds());
+                            o.watchdog_guard = None;
+                        }
+                        offspring_replace(&mut brood, &mut state, pid, "missed heartbeat");
+                    }
+                },
+                TimerAction::SampleResources => {
+                    let mut offenders = vec![];
+                    for (pid, o) in &brood {
+                        // Offenders already on their way out were warned about before
+                        if o.state != OffspringState::Healthy || being_replaced(&brood, *pid) {
+                            continue;
+                        }
+                        let stat = match procfs::stat(*pid) {
+                            Ok(stat) => stat,
+                            Err(e) => {
+                                debug!(o.log, "couldn't sample resource usage"; "err" => e);
+                                continue;
+                            }
+                        };
+                        let rss_mb = stat.rss / (1024 * 1024);
+                        let limit = match (o.cfg(&state).max_rss_mb, o.cfg(&state).max_cpu_secs) {
+                            (Some(max), _) if rss_mb > max => "memory",
+                            (_, Some(max)) if stat.cpu_secs > max => "CPU time",
+                            _ => continue,
+                        };
+                        warn!(o.log, "over resource limit";
+                            "limit" => limit,
+                            "rss_mb" => rss_mb,
+                            "cpu_secs" => stat.cpu_secs);
+                        offenders.push((*pid, limit));
+                    }
+                    for (pid, limit) in offenders {
+                        offspring_replace(&mut brood, &mut state, pid,
+                                          &format!("over {} limit", limit));
+                    }
+                },
+                TimerAction::CheckAge(pid) => {
+                    if brood.get(&pid).map(|o| o.state) != Some(OffspringState::Healthy) {
+                        continue;
+                    }
+                    if replacement_underway(&brood, &brood[&pid].pool) {
+                        // One at a time; try again once the current successor has had a chance
+                        // to come up
+                        let o = brood.get_mut(&pid).unwrap();
+                        let retry = o.cfg(&state).childhood;
+                        debug!(o.log, "reached max age, but waiting for another replacement");
+                        o.schedule_age_check(&mut state, retry);
+                        continue;
+                    }
+                    brood.get_mut(&pid).unwrap().age_guard = None;
+                    offspring_replace(&mut brood, &mut state, pid, "reached max age");
+                },
+                TimerAction::ReExec => {
+                    // Only returns if something went wrong
+                    if let Err(e) = reexec(&brood, &state) {
+                        error!(state.log, "couldn't re-exec master"; "err" => e);
+                    }
+                },
+                TimerAction::Upgrade(index, generation, was_upgrading) => {
+                    let name = state.pools[index].cfg.name.clone();
+                    // Superseded by a later upgrade, or too late to bother
+                    if state.pools[index].generation != generation || state.draining.is_some() {
+                        continue;
+                    }
+                    replace_generation(&mut brood, &mut state, &name, was_upgrading);
+                },
+                TimerAction::CheckForeign => {
+                    let gone: Vec<u32> = brood.values()
+                        .filter(|o| o.foreign && !still_running(o.pid, o.starttime))
+                        .map(|o| o.pid)
+                        .collect();
+                    for pid in gone {
+                        let o = brood.remove(&pid).unwrap();
+                        info!(o.log, "adopted child exited");
+                        // Like one of our own, a live child gets respawned (unless its successor
+                        // is already on the way)
+                        if o.holds_slot() && state.draining.is_none() &&
+                           !brood.values().any(|s| s.replaces == Some(pid) && s.is_active()) {
+                            if let Ok(successor) = spawn_into(&mut state, &o.pool, o.slot, None, 0) {
+                                brood.insert(successor.pid, successor);
+                            }
+                        }
+                    }
+                    if !brood.values().any(|o| o.foreign) {
+                        foreign_poll.take();
+                    }
+                },
+                TimerAction::DrainTimeout => {
+                    warn!(state.log, "children still running after shutdown";
+                        "count" => brood.len());
+                    for (pid, o) in &mut brood {
+                        if state.cfg.kill_on_exit {
+                            warn!(o.log, "killing straggler");
+                            nix::sys::signal::kill(o.signal_target(Signal::KILL),
+                                                   nix::sys::signal::Signal::SIGKILL)
+                                .ok();
+                            nix::sys::wait::waitpid(*pid as i32, None).ok();
+                        } else {
+                            warn!(o.log, "leaving child behind");
                         }
                     }
+                    exit_code = 1;
+                    run = false;
                 },
             },
+            probe_rx.recv() -> res => {
+                offspring_probed(&mut brood, &mut state, res.expect("Error with probe thread"));
+            },
             ctrl_req_rx.recv() -> maybe_req =>
                 if let Some(req) = maybe_req { match req.action {
+                    CtrlAction::Increment | CtrlAction::Decrement | CtrlAction::UpgradeAll |
+                    CtrlAction::Reload | CtrlAction::ReExec if state.draining.is_some() => {
+                        req.tx.send("Shutting down, ignoring".to_string());
+                    },
                     CtrlAction::Increment => {
-                        let o = Offspring::spawn(&mut state).unwrap();
-                        let pid = o.process.id();
+                        let name = match state.target_pool(&req.pool) {
+                            Ok(name) => name,
+                            Err(e) => {
+                                req.tx.send(e);
+                                continue;
+                            }
+                        };
+                        let slot = free_slot(&brood, &name);
+                        let o = match spawn_into(&mut state, &name, slot, None, 0) {
+                            Ok(o) => o,
+                            Err(e) => {
+                                req.tx.send(format!("Couldn't spawn: {}", e));
+                                continue;
+                            }
+                        };
+                        let pid = o.pid;
                         brood.insert(pid, o);
+                        let cfg = &mut state.pool_mut(&name).cfg;
                         req.tx.send(format!("Spawned! Went from {} to {}",
-                                            state.cfg.count,
-                                            state.cfg.count+1));
-                        state.cfg.count += 1;
+                                            cfg.count,
+                                            cfg.count+1));
+                        cfg.count += 1;
                     },
                     CtrlAction::Decrement => {
-                        if state.cfg.count <= 0 {
+                        let name = match state.target_pool(&req.pool) {
+                            Ok(name) => name,
+                            Err(e) => {
+                                req.tx.send(e);
+                                continue;
+                            }
+                        };
+                        if state.pool(&name).cfg.count <= 0 {
                             req.tx.send("Already at count=0, no-op".to_string());
                             continue;
                         }
-                        let mut done = false;
+                        // Give up the highest slot (along with any successor coming up in it)
+                        let highest = brood.values()
+                            .filter(|o| o.pool == name && o.holds_slot())
+                            .map(|o| o.slot)
+                            .max();
+                        let slot = match highest {
+                            Some(slot) => slot,
+                            None => {
+                                req.tx.send("No live workers to shutdown! :(".to_string());
+                                continue;
+                            }
+                        };
                         for (_, o) in &mut brood {
-                            if o.is_active() {
+                            if o.pool == name && o.holds_slot() && o.slot == slot {
                                 o.shutdown(&mut state);
-                                req.tx.send(format!("Notified! Went from {} to {}",
-                                                    state.cfg.count,
-                                                    state.cfg.count-1));
-                                state.cfg.count -= 1;
-                                done = true;
-                                break;
                             }
                         }
-                        if !done {
-                            req.tx.send("No live workers to shutdown! :(".to_string());
-                        }
+                        let cfg = &mut state.pool_mut(&name).cfg;
+                        req.tx.send(format!("Notified! Went from {} to {}",
+                                            cfg.count,
+                                            cfg.count-1));
+                        cfg.count -= 1;
                     },
                     CtrlAction::SigAll(sig) => {
+                        let names = match state.target_pools(&req.pool) {
+                            Ok(names) => names,
+                            Err(e) => {
+                                req.tx.send(e);
+                                continue;
+                            }
+                        };
                         for (_, o) in &mut brood {
-                            o.signal(sig);
+                            if names.contains(&o.pool) {
+                                o.signal(sig);
+                            }
                         }
                         req.tx.send("Signalled all children!".to_string());
                     },
                     CtrlAction::ShutdownAll => {
-                        let mut pid_list = vec![];
-                        for (pid, o) in &mut brood {
-                            if o.is_active() {
-                                o.shutdown(&mut state);
-                                pid_list.push(pid);
-                            }
+                        info!(state.log, "Exiting! Gracefully shutting down children first");
+                        for (_, o) in &mut brood {
+                            o.shutdown(&mut state);
                         }
+                        begin_drain(&brood, &mut state);
                         req.tx.send("Sent shutdown to all children!".to_string());
                     },
                     CtrlAction::UpgradeAll => {
-                        let keys: Vec<u32> = brood.keys().cloned().collect();
-                        for pid in keys {
-                            let mut successor = {
-                                let o = brood.get_mut(&pid).unwrap();
-                                if !o.is_active() {
-                                    continue;
-                                }
-                                o.respawn(&mut state).unwrap()
-                            };
-                            successor.attempts = 0;
-                            brood.insert(successor.process.id(), successor);
+                        let names = match state.target_pools(&req.pool) {
+                            Ok(names) => names,
+                            Err(e) => {
+                                req.tx.send(e);
+                                continue;
+                            }
+                        };
+                        for name in names {
+                            upgrade_pool(&mut brood, &mut state, &name);
                         }
                         req.tx.send("Upgrading all children!".to_string());
                     },
+                    CtrlAction::Reload => {
+                        match reload(&mut brood, &mut state) {
+                            Ok(()) => req.tx.send("Reloaded config, upgrading all children!"
+                                .to_string()),
+                            Err(e) => {
+                                error!(state.log, "config not reloaded"; "err" => e.clone());
+                                req.tx.send(format!("Config not reloaded: {}", e));
+                            }
+                        }
+                    },
+                    CtrlAction::ReopenLogs => {
+                        reopen_logs(&state);
+                        req.tx.send("Reopened log files!".to_string());
+                    },
                     CtrlAction::Status => {
-                        req.tx.send("UNIMPLEMENTED".to_string());
+                        match state.target_pools(&req.pool) {
+                            Ok(names) => req.tx.send(status_summary(&brood, &state, &names)),
+                            Err(e) => req.tx.send(e),
+                        }
+                    },
+                    CtrlAction::ReExec => {
+                        info!(state.log, "re-executing master");
+                        req.tx.send("Re-executing master!".to_string());
+                        // Give the control socket thread a moment to send the reply
+                        let t_tx = state.timer_tx.clone();
+                        state.timer.schedule_with_delay(Duration::milliseconds(100), move || {
+                            t_tx.send(TimerAction::ReExec);
+                        }).ignore();
                     },
                     CtrlAction::ManualAck(pid) => {
-                        if let Some(o) = brood.get_mut(&pid) {
-                            if o.is_active() {
-                                o.state = OffspringState::Healthy;
-                            }
+                        if brood.get(&pid).map(|o| o.state) == Some(OffspringState::Infancy) {
+                            offspring_healthy(&mut brood, &mut state, pid);
                         }
                         req.tx.send("Acknowledged!".to_string());
                     },
+                    CtrlAction::Heartbeat(pid) => {
+                        match brood.get_mut(&pid) {
+                            Some(o) => {
+                                debug!(o.log, "heartbeat");
+                                if o.state == OffspringState::Healthy {
+                                    o.feed_watchdog(&mut state);
+                                }
+                                req.tx.send("Heartbeat!".to_string());
+                            }
+                            None => {
+                                req.tx.send(format!("Unknown pid: {}", pid));
+                            }
+                        }
+                    },
                 }
             },
             signal_rx.recv() -> sig => match sig.expect("Error with signal handler") {
@@ -366,37 +1060,79 @@ fn shepard(mut state: EinState, signal_rx: Receiver<Signal>) {
                         match res {
                             Ok(nix::sys::wait::WaitStatus::Exited(pid, _)) |
                             Ok(nix::sys::wait::WaitStatus::Signaled(pid, _, _)) => {
+                                if state.helpers.reaped(pid as u32, res.unwrap()) {
+                                    continue;
+                                }
+                                let o = match brood.remove(&(pid as u32)) {
+                                    Some(o) => o,
+                                    None => {
+                                        // Reparented to us after its own parent exited
+                                        info!(state.log, "reaped orphan";
+                                            "pid" => pid, "status" => format!("{:?}", res.unwrap()));
+                                        continue;
+                                    }
+                                };
                                 info!(state.log, "child exited"; "child_pid" => pid);
-                                if let Some(mut o) = brood.remove(&(pid as u32)) { match o.state {
+                                last_exit = Some(res.unwrap());
+                                if o.process_group {
+                                    // Whatever the child left behind in its group has no one
+                                    // else to clean it up (and may be holding our sockets)
+                                    nix::sys::signal::kill(-pid, nix::sys::signal::Signal::SIGKILL)
+                                        .ok();
+                                }
+                                if o.holds_slot() && state.draining.is_none() {
+                                    let mut env = offspring_env(&o);
+                                    env.extend(exit_env(res.unwrap()));
+                                    run_hooks(&state, HookEvent::Crash, env);
+                                }
+                                match o.state {
+                                    // No respawning while shutting down
+                                    OffspringState::Infancy | OffspringState::Healthy
+                                            if state.draining.is_some() => (),
                                     OffspringState::Infancy => {
-                                        if o.attempts + 1 >= state.cfg.retries {
+                                        if o.attempts + 1 >= o.cfg(&state).retries {
                                             warn!(state.log, "ran out of retries while spawning";
                                                 "child_pid" => pid);
-                                        } else {
-                                            let mut successor = o.respawn(&mut state).unwrap();
-                                            successor.attempts = o.attempts + 1;
-                                            brood.insert(successor.process.id(), successor);
+                                            run_hooks(&state,
+                                                      HookEvent::RetriesExhausted,
+                                                      offspring_env(&o));
+                                        } else if let Ok(successor) =
+                                                      spawn_into(&mut state,
+                                                                 &o.pool,
+                                                                 o.slot,
+                                                                 Some(o.pid),
+                                                                 o.attempts + 1) {
+                                            brood.insert(successor.pid, successor);
                                         }
                                     },
                                     OffspringState::Healthy => {
-                                        let mut successor = o.respawn(&mut state).unwrap();
-                                        successor.replaces = Some(pid as u32);
-                                        brood.insert(successor.process.id(), successor);
+                                        if let Ok(successor) =
+                                               spawn_into(&mut state, &o.pool, o.slot, Some(o.pid), 0) {
+                                            brood.insert(successor.pid, successor);
+                                        }
                                     },
-                                    OffspringState::Notified => (),
-                                    OffspringState::Dead => {
-                                        error!(state.log, "double-notified death";
-                                            "child_pid" => pid);
-                                    }
-                                } };
+                                    // On its way out already; Dead ones were sent KILL by
+                                    // the last step of the shutdown sequence
+                                    OffspringState::Notified | OffspringState::Dead => (),
+                                };
                             },
                             Ok(nix::sys::wait::WaitStatus::StillAlive) => break,
                             Ok(_) => {
                                 info!(state.log, "SIGCHLD we don't care about";
                                     "value" => format!("{:?}", res));
                             },
+                            // As init, there's no telling what else might come along to be
+                            // reaped; whether to carry on depends on the offspring alone. Nor are
+                            // adopted children ever reaped by us.
+                            Err(nix::Error::Sys(nix::Errno::ECHILD))
+                                    if state.cfg.init_mode || brood.values().any(|o| o.foreign) => {
+                                break;
+                            },
                             Err(nix::Error::Sys(nix::Errno::ECHILD)) => {
-                                warn!(state.log, "all children are dead, bailing");
+                                if state.draining.is_none() {
+                                    warn!(state.log, "all children are dead, bailing");
+                                    exit_code = 1;
+                                }
                                 run = false;
                                 break;
                             },
@@ -408,20 +1144,22 @@ fn shepard(mut state: EinState, signal_rx: Receiver<Signal>) {
                         }
                     };
                 },
+                Signal::HUP if state.draining.is_some() => {
+                    info!(state.log, "Shutting down, ignoring HUP");
+                },
                 Signal::HUP => {
-                    let keys: Vec<u32> = brood.keys().cloned().collect();
-                    for pid in keys {
-                        let mut successor = {
-                            let o = brood.get_mut(&pid).unwrap();
-                            if !o.is_active() {
-                                continue;
-                            }
-                            o.respawn(&mut state).unwrap()
-                        };
-                        successor.attempts = 0;
-                        brood.insert(successor.process.id(), successor);
+                    if let Err(e) = reload(&mut brood, &mut state) {
+                        error!(state.log, "config not reloaded"; "err" => e);
+                    } },
+                Signal::USR1 => {
+                    reopen_logs(&state);
+                    if state.cfg.forward_usr1 {
+                        info!(state.log, "passing signal to children"; "signal" => "USR1");
+                        for (_, o) in &mut brood {
+                            o.signal(Signal::USR1);
+                        }
                     } },
-                Signal::TTIN | Signal::TTOU | Signal::USR1 | Signal::STOP | Signal::CONT => {
+                Signal::TTIN | Signal::TTOU | Signal::STOP | Signal::CONT => {
                     let sig = sig.unwrap();
                     info!(state.log, "passing signal to children";
                         "signal" => format!("{:?}", sig));
@@ -429,20 +1167,26 @@ fn shepard(mut state: EinState, signal_rx: Receiver<Signal>) {
                         o.signal(sig);
                     } },
                 Signal::INT | Signal::USR2 => {
-                    info!(state.log,
-                        "Exiting! Gracefully shutting down children first, but won't wait");
+                    info!(state.log, "Exiting! Gracefully shutting down children first");
                     for (_, o) in &mut brood {
                         o.shutdown(&mut state);
                     }
-                    run = false;
+                    begin_drain(&brood, &mut state);
                 },
-                Signal::TERM | Signal::QUIT => {
-                    info!(state.log,
-                        "Exiting! Killing children first, but won't wait.");
+                // Container runtimes stop things with TERM (then KILL, if it takes too long)
+                Signal::TERM if state.cfg.init_mode => {
+                    info!(state.log, "Exiting! Gracefully shutting down children first");
                     for (_, o) in &mut brood {
-                        o.terminate(&mut state);
+                        o.shutdown(&mut state);
                     }
-                    run = false;
+                    begin_drain(&brood, &mut state);
+                },
+                Signal::TERM | Signal::QUIT => {
+                    info!(state.log, "Exiting! Terminating children first");
+                    for (_, o) in &mut brood {
+                        o.terminate(&mut state);
+                    }
+                    begin_drain(&brood, &mut state);
                 },
                 default => {
                     info!(state.log, "Unexpected signal (ignoring)";
@@ -450,56 +1194,849 @@ fn shepard(mut state: EinState, signal_rx: Receiver<Signal>) {
                 },
             },
         }
+        check_upgrades(&brood, &mut state);
+        if !state.dropped_socks.is_empty() {
+            let dropped = state.dropped_socks.split_off(0);
+            state.dropped_socks = bind::remove_unused(dropped, &state.log);
+        }
+        if state.cfg.init_mode && state.draining.is_none() && brood.is_empty() &&
+           state.pools.iter().any(|p| p.cfg.count > 0) {
+            warn!(state.log, "all children are dead, bailing");
+            exit_code = match last_exit.map(exit_status) {
+                Some(0) | None => 1,
+                Some(code) => code,
+            };
+            run = false;
+        }
+        if state.draining.is_some() && brood.is_empty() {
+            info!(state.log, "all children have exited");
+            run = false;
+        }
         if !run {
             break;
         }
     }
 
-    info!(state.log, "reaping children";
-        "count" => brood.len());
-    for (pid, o) in &brood {
-        if o.is_active() {
-            nix::sys::wait::waitpid(*pid as i32, Some(nix::sys::wait::WNOHANG)).ok();
+    if state.cfg.terminate_orphans {
+        for pid in orphans(&brood, &state) {
+            warn!(state.log, "killing orphan"; "pid" => pid);
+            nix::sys::signal::kill(pid as i32, nix::sys::signal::Signal::SIGKILL).ok();
+            nix::sys::wait::waitpid(pid as i32, None).ok();
+        }
+    }
+
+    // Anything left behind is still on record, for a successor to adopt
+    if let Some(ref path) = state.cfg.state_file {
+        if brood.is_empty() {
+            fs::remove_file(path).ok();
+        }
+    }
+    for pool in &state.pools {
+        bind::close_all(&pool.bind_fds, &pool.log);
+    }
+    if let Some(pid_file) = state.pid_file.take() {
+        pid_file.remove();
+    }
+    if let Some(readiness) = state.readiness.take() {
+        readiness.fail(&format!("exited during startup (status {})", exit_code));
+    }
+
+    info!(state.log, "done, exiting"; "status" => exit_code);
+    run_hooks(&state,
+              HookEvent::Exit,
+              vec![("EINHORN_EXIT_STATUS".to_string(), exit_code.to_string())]);
+    exit_code
+}
+
+// Reports back to the foreground process (see --daemonize) once the initial children are all
+// healthy, and only then writes the pid file. If they didn't all make it, we shut down instead.
+fn check_ready(brood: &mut HashMap<u32, Offspring>, state: &mut EinState) {
+    if state.readiness.is_none() || brood.values().any(|o| o.state == OffspringState::Infancy) {
+        return;
+    }
+    let readiness = state.readiness.take().unwrap(); // safe
+    let short = state.pools
+        .iter()
+        .find(|p| {
+            let healthy = brood.values()
+                .filter(|o| o.pool == p.cfg.name && o.state == OffspringState::Healthy)
+                .count();
+            (healthy as u64) < p.cfg.count
+        })
+        .map(|p| p.cfg.name.clone());
+    let problem = match (short, state.pid_file.as_mut()) {
+        (Some(name), _) => Some(format!("children of pool '{}' failed to start", name)),
+        (None, Some(pid_file)) => {
+            pid_file.write(nix::unistd::getpid() as u32)
+                .err()
+                .map(|e| format!("couldn't write pid file: {}", e))
+        }
+        (None, None) => None,
+    };
+    match problem {
+        Some(problem) => {
+            error!(state.log, "startup failed, shutting down"; "err" => problem.clone());
+            readiness.fail(&problem);
+            for (_, o) in brood.iter_mut() {
+                o.shutdown(state);
+            }
+            begin_drain(brood, state);
+        }
+        None => {
+            info!(state.log, "started up");
+            readiness.succeed();
+        }
+    }
+}
+
+// Replaces the running master with a fresh exec of the einhyrningsins binary (which may have been
+// upgraded on disk). The listening sockets and control socket are inherited as file descriptors,
+// the children are inherited for free (exec keeps our pid), and the rest of the state is passed
+// along as JSON in an environment variable.
+fn reexec(brood: &HashMap<u32, Offspring>, state: &EinState) -> Result<(), String> {
+    let saved = saved_state(brood, state);
+
+    // argv[0] has to keep working from our (possibly changed) working directory, for this exec
+    // and the next one
+    let args: Vec<CString> = try!(env::args()
+        .enumerate()
+        .map(|(i, a)| match state.exe {
+            Some(ref exe) if i == 0 => exe.clone(),
+            _ => a,
+        })
+        .map(|a| CString::new(a).map_err(|e| e.to_string()))
+        .collect());
+    // The control socket and captured output pipes are opened close-on-exec; the bound sockets
+    // never were
+    try!(set_cloexec(state.ctrl_fd, false).map_err(|e| format!("{:?}", e)));
+    for o in brood.values() {
+        for c in o.open_output() {
+            set_cloexec(c.fd, false).ok();
+        }
+    }
+    env::set_var("EINHYRNINGSINS_STATE", saved.dump());
+    let err = nix::unistd::execvp(&args[0], &args).unwrap_err();
+
+    // Still here, so exec failed; put things back the way they were
+    env::remove_var("EINHYRNINGSINS_STATE");
+    set_cloexec(state.ctrl_fd, true).ok();
+    for o in brood.values() {
+        for c in o.open_output() {
+            set_cloexec(c.fd, true).ok();
+        }
+    }
+    Err(format!("{:?}", err))
+}
+
+// Everything a successor needs to take over from us: passed along on re-exec, and kept in the
+// state file
+fn saved_state(brood: &HashMap<u32, Offspring>, state: &EinState) -> json::JsonValue {
+    let children: Vec<json::JsonValue> = brood.values().map(|o| o.to_json()).collect();
+    let pools: Vec<json::JsonValue> = state.pools
+        .iter()
+        .map(|p| {
+            object!{
+                "name" => p.cfg.name.clone(),
+                "bind_fds" => p.bind_fds.clone(),
+                "count" => p.cfg.count,
+                "generation" => p.generation
+            }
+        })
+        .collect();
+    let pid = nix::unistd::getpid() as u32;
+    object!{
+        "master" => object!{
+            "pid" => pid,
+            "starttime" => procfs::stat(pid).ok().map(|s| s.starttime)
+        },
+        "ctrl_fd" => state.ctrl_fd,
+        "pools" => pools,
+        "children" => children
+    }
+}
+
+// Records the brood in the state file (when it has changed), for a successor to adopt should we
+// crash. The file is replaced atomically, so it is never seen half-written.
+fn save_state(brood: &HashMap<u32, Offspring>, state: &EinState, last_saved: &mut String) {
+    let path = match state.cfg.state_file {
+        Some(ref path) => path,
+        None => return,
+    };
+    let dump = saved_state(brood, state).dump();
+    if dump == *last_saved {
+        return;
+    }
+    let tmp = format!("{}.tmp", path);
+    let res = fs::File::create(&tmp)
+        .and_then(|mut f| f.write_all(dump.as_bytes()))
+        .and_then(|_| fs::rename(&tmp, path));
+    if let Err(e) = res {
+        warn!(state.log, "couldn't write state file"; "path" => path.clone(), "err" => e.to_string());
+    }
+    // Either way; no point retrying (and warning) until something changes
+    *last_saved = dump;
+}
+
+// Reads the state file left by a crashed master, keeping only the children still running
+fn load_survivors(path: &str) -> Option<json::JsonValue> {
+    let mut raw = String::new();
+    match fs::File::open(path) {
+        Ok(mut f) => {
+            if let Err(e) = f.read_to_string(&mut raw) {
+                println!("Couldn't read state file {}: {}", path, e);
+                exit(-1);
+            }
+        }
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {
+            println!("No state file at {}, nothing to adopt", path);
+            return None;
+        }
+        Err(e) => {
+            println!("Couldn't read state file {}: {}", path, e);
+            exit(-1);
+        }
+    }
+    let mut saved = match json::parse(&raw) {
+        Ok(saved) => saved,
+        Err(e) => {
+            println!("Couldn't parse state file {}: {}", path, e);
+            exit(-1);
+        }
+    };
+    if let Some(pid) = saved["master"]["pid"].as_u32() {
+        if still_running(pid, saved["master"]["starttime"].as_u64()) {
+            println!("Master {} is still running, not adopting its children", pid);
+            exit(-1);
+        }
+    }
+    let survivors: Vec<json::JsonValue> = saved["children"]
+        .members()
+        .filter(|c| c["pid"].as_u32().map_or(false, |pid| still_running(pid, c["starttime"].as_u64())))
+        .cloned()
+        .collect();
+    println!("Adopting {} children left running by the previous master", survivors.len());
+    saved["children"] = survivors.into();
+    Some(saved)
+}
+
+// Whether `pid` is still the process we knew by that pid, and hasn't exited
+fn still_running(pid: u32, starttime: Option<u64>) -> bool {
+    match procfs::stat(pid) {
+        Ok(stat) => !stat.zombie && starttime.map_or(true, |t| t == stat.starttime),
+        Err(_) => false,
+    }
+}
+
+fn set_cloexec(fd: RawFd, on: bool) -> nix::Result<()> {
+    let flags = if on {
+        nix::fcntl::FD_CLOEXEC
+    } else {
+        nix::fcntl::FdFlag::empty()
+    };
+    nix::fcntl::fcntl(fd, nix::fcntl::FcntlArg::F_SETFD(flags)).map(|_| ())
+}
+
+// Enters the shutdown phase: no more respawns, and the master exits once every child is gone or
+// the slowest pool's whole shutdown sequence (plus a graceperiod) has passed.
+fn begin_drain(brood: &HashMap<u32, Offspring>, state: &mut EinState) {
+    if state.draining.is_some() {
+        return;
+    }
+    if state.cfg.terminate_orphans {
+        for pid in orphans(brood, state) {
+            info!(state.log, "terminating orphan"; "pid" => pid);
+            nix::sys::signal::kill(pid as i32, nix::sys::signal::Signal::SIGTERM).ok();
+        }
+    }
+    let timeout = state.pools
+        .iter()
+        .map(|p| {
+            p.cfg.shutdown_steps.iter().fold(p.cfg.graceperiod, |acc, &(_, wait)| acc + wait)
+        })
+        .max()
+        .unwrap_or(Duration::zero());
+    let t_tx = state.timer_tx.clone();
+    state.draining = Some(state.timer.schedule_with_delay(timeout, move || {
+        t_tx.send(TimerAction::DrainTimeout);
+    }));
+}
+
+// Processes we've adopted as a subreaper: our children which aren't offspring or helpers
+fn orphans(brood: &HashMap<u32, Offspring>, state: &EinState) -> Vec<u32> {
+    procfs::children(nix::unistd::getpid() as u32)
+        .into_iter()
+        .filter(|pid| !brood.contains_key(pid) && !state.helpers.is_helper(*pid))
+        .collect()
+}
+
+// Marks a child healthy, which retires the child it replaces (if any) and starts liveness probes.
+fn offspring_healthy(brood: &mut HashMap<u32, Offspring>, state: &mut EinState, pid: u32) {
+    let replaces = match brood.get_mut(&pid) {
+        Some(o) => {
+            o.state = OffspringState::Healthy;
+            o.timer_guard = None;
+            o.probe_failures = 0;
+            o.schedule_probe(state);
+            o.feed_watchdog(state);
+            o.schedule_retirement(state);
+            o.replaces
+        }
+        None => return,
+    };
+    if let Some(old_pid) = replaces {
+        if let Some(old) = brood.get_mut(&old_pid) {
+            old.shutdown(state);
+        }
+    }
+}
+
+// A child that never became healthy (no ack in time, or failing readiness probes) is terminated,
+// and respawned if there are retries left.
+fn offspring_unhealthy(brood: &mut HashMap<u32, Offspring>, state: &mut EinState, pid: u32) {
+    let successor = match brood.get_mut(&pid) {
+        Some(o) => {
+            let successor = if state.draining.is_some() {
+                None
+            } else if o.attempts + 1 >= o.cfg(state).retries {
+                warn!(o.log, "ran out of retries");
+                run_hooks(state, HookEvent::RetriesExhausted, offspring_env(o));
+                None
+            } else {
+                spawn_into(state, &o.pool, o.slot, Some(o.pid), o.attempts + 1).ok()
+            };
+            o.terminate(state);
+            successor
+        }
+        None => return,
+    };
+    if let Some(successor) = successor {
+        brood.insert(successor.pid, successor);
+    }
+}
+
+// Gracefully swaps out a live child: the successor retires it once healthy, so capacity never
+// drops. No-op if a replacement is already underway.
+fn offspring_replace(brood: &mut HashMap<u32, Offspring>,
+                     state: &mut EinState,
+                     pid: u32,
+                     reason: &str) {
+    if state.draining.is_some() {
+        return;
+    }
+    if being_replaced(brood, pid) {
+        return;
+    }
+    let successor = match brood.get_mut(&pid) {
+        Some(o) => {
+            if !o.is_active() {
+                return;
+            }
+            warn!(o.log, "replacing child"; "reason" => reason);
+            match spawn_into(state, &o.pool, o.slot, Some(o.pid), 0) {
+                Ok(successor) => successor,
+                Err(_) => return,
+            }
+        }
+        None => return,
+    };
+    brood.insert(successor.pid, successor);
+}
+
+// Starts a new generation of the pool, replacing each of its children with a fresh spawn. With
+// pre-upgrade hooks, that waits until they are done, which happens in the background so the event
+// loop carries on meanwhile.
+fn upgrade_pool(brood: &mut HashMap<u32, Offspring>, state: &mut EinState, name: &str) {
+    let was_upgrading = {
+        let pool = state.pool_mut(name);
+        let was_upgrading = pool.upgrading;
+        pool.generation += 1;
+        pool.upgrading = true;
+        info!(pool.log, "upgrading"; "generation" => pool.generation);
+        was_upgrading
+    };
+    let hooks: Vec<String> = state.cfg
+        .hooks
+        .iter()
+        .filter(|&&(e, _)| e == HookEvent::PreUpgrade)
+        .map(|&(_, ref cmdline)| cmdline.clone())
+        .collect();
+    if hooks.is_empty() {
+        replace_generation(brood, state, name, was_upgrading);
+        return;
+    }
+    let index = state.pools.iter().position(|p| p.cfg.name == name).unwrap(); // safe
+    let generation = state.pools[index].generation;
+    let env = pool_env(&state.pools[index]);
+    let timeout = state.cfg.hook_timeout;
+    let helpers = state.helpers.clone();
+    let log = state.log.clone();
+    let t_tx = state.timer_tx.clone();
+    thread::spawn(move || {
+        for cmdline in &hooks {
+            hook::run(HookEvent::PreUpgrade, cmdline, &env, timeout, &helpers, &log);
+        }
+        t_tx.send(TimerAction::Upgrade(index, generation, was_upgrading));
+    });
+}
+
+// Spawns a successor for each of the pool's children from before the current generation (those
+// that came along during the pre-upgrade hooks are new enough already)
+fn replace_generation(brood: &mut HashMap<u32, Offspring>,
+                      state: &mut EinState,
+                      name: &str,
+                      was_upgrading: bool) {
+    let generation = state.pool(name).generation;
+    let keys: Vec<u32> = brood.iter()
+        .filter(|&(_, o)| o.pool == name && o.generation < generation)
+        .map(|(pid, _)| *pid)
+        .collect();
+    let mut successors: Vec<Offspring> = vec![];
+    for pid in keys {
+        let res = {
+            let o = brood.get_mut(&pid).unwrap();
+            // Children already on their way out don't need a successor
+            if !o.holds_slot() {
+                continue;
+            }
+            o.respawn(state)
+        };
+        match res {
+            Ok(mut successor) => {
+                successor.attempts = 0;
+                successors.push(successor);
+            }
+            Err(e) => {
+                error!(state.pool(name).log, "upgrade failed, keeping the old generation";
+                    "err" => e);
+                // Nothing is retired until its successor is healthy, so dropping the successors
+                // spawned so far leaves the pool as it was
+                for mut s in successors {
+                    s.terminate(state);
+                    brood.insert(s.pid, s);
+                }
+                let pool = state.pool_mut(name);
+                pool.generation -= 1;
+                pool.upgrading = was_upgrading;
+                return;
+            }
         }
     }
-    info!(state.log, "done, exiting");
+    for s in successors {
+        brood.insert(s.pid, s);
+    }
+}
+
+// Re-reads the configuration (command line and config file) and applies it: sockets are bound,
+// counts adjusted, and then each pool is upgraded so its children pick up the new settings.
+// Nothing is touched unless the whole new configuration checks out.
+fn reload(brood: &mut HashMap<u32, Offspring>, state: &mut EinState) -> Result<(), String> {
+    info!(state.log, "reloading config");
+    let args: Vec<String> = env::args().skip(1).collect();
+    let mut new_cfg = try!(config::load(&args));
+    try!(check_config(&new_cfg));
+    for pool in &state.pools {
+        if !new_cfg.pools.iter().any(|p| p.name == pool.cfg.name) {
+            return Err(format!("Can't remove pool '{}' without restarting", pool.cfg.name));
+        }
+    }
+
+    // Bind any new sockets up front, so that a failure leaves everything as it was. Sockets that
+    // are still wanted are kept as they are.
+    let mut pool_fds: Vec<Vec<RawFd>> = vec![];
+    let mut fresh_fds: Vec<RawFd> = vec![];
+    for pool_cfg in &new_cfg.pools {
+        let kept: Vec<(String, RawFd)> = match state.pools
+            .iter()
+            .find(|p| p.cfg.name == pool_cfg.name) {
+            Some(p) if p.cfg.ipv4_only == pool_cfg.ipv4_only &&
+                       p.cfg.ipv6_only == pool_cfg.ipv6_only => {
+                p.cfg.bind_slugs.iter().cloned().zip(p.bind_fds.iter().cloned()).collect()
+            }
+            _ => vec![],
+        };
+        let mut unbound = pool_cfg.clone();
+        unbound.bind_slugs.retain(|b| !kept.iter().any(|&(ref k, _)| k == b));
+        let mut bound = match bind::bind_all(&unbound, &[], &state.log) {
+            Ok(fds) => fds.into_iter(),
+            Err(e) => {
+                for fd in fresh_fds {
+                    nix::unistd::close(fd).ok();
+                }
+                return Err(e);
+            }
+        };
+        let fds: Vec<RawFd> = pool_cfg.bind_slugs
+            .iter()
+            .map(|b| match kept.iter().find(|&&(ref k, _)| k == b) {
+                Some(&(_, fd)) => fd,
+                None => {
+                    let fd = bound.next().unwrap(); // safe
+                    fresh_fds.push(fd);
+                    fd
+                }
+            })
+            .collect();
+        pool_fds.push(fds);
+    }
+    let mut outputs = vec![];
+    for pool_cfg in &new_cfg.pools {
+        let existing = state.pools
+            .iter()
+            .find(|p| p.cfg.name == pool_cfg.name)
+            .and_then(|p| p.output.clone());
+        outputs.push(match existing {
+            Some(ref out) if Some(out.path()) == pool_cfg.output_file.as_ref().map(|p| &p[..]) => {
+                existing.clone()
+            }
+            _ => {
+                match open_output_file(pool_cfg) {
+                    Ok(out) => out,
+                    Err(e) => {
+                        for fd in fresh_fds {
+                            nix::unistd::close(fd).ok();
+                        }
+                        return Err(e);
+                    }
+                }
+            }
+        });
+    }
+
+    for (setting, old, new) in config::diff(&config::describe(&state.cfg),
+                                            &config::describe(&new_cfg)) {
+        match setting {
+            "socket-path" | "verbose" | "syslog" | "log-file" | "subreaper" | "state-file" |
+            "pid-file" | "daemonize" | "working-dir" => {
+                warn!(state.log, "setting can't be changed without restarting, ignoring";
+                      "setting" => setting, "old" => old, "new" => new);
+            }
+            _ => info!(state.log, "config changed"; "setting" => setting, "old" => old, "new" => new),
+        }
+    }
+    new_cfg.ctrl_path = state.cfg.ctrl_path.clone();
+    new_cfg.verbose = state.cfg.verbose;
+    new_cfg.syslog = state.cfg.syslog;
+    new_cfg.log_file = state.cfg.log_file.clone();
+    new_cfg.subreaper = state.cfg.subreaper;
+    new_cfg.state_file = state.cfg.state_file.clone();
+    new_cfg.pid_file = state.cfg.pid_file.clone();
+    new_cfg.daemonize = state.cfg.daemonize;
+    new_cfg.working_dir = state.cfg.working_dir.clone();
+    state.cfg = new_cfg.clone();
+    schedule_sampling(state);
+
+    for ((pool_cfg, fds), output) in new_cfg.pools.iter().zip(pool_fds).zip(outputs) {
+        let name = pool_cfg.name.clone();
+        if state.pools.iter().any(|p| p.cfg.name == name) {
+            let dropped = {
+                let pool = state.pool_mut(&name);
+                for (setting, old, new) in config::diff(&config::describe_pool(&pool.cfg),
+                                                        &config::describe_pool(pool_cfg)) {
+                    info!(pool.log, "config changed";
+                          "setting" => setting, "old" => old, "new" => new);
+                }
+                let dropped: Vec<RawFd> =
+                    pool.bind_fds.iter().cloned().filter(|fd| !fds.contains(fd)).collect();
+                pool.cfg = pool_cfg.clone();
+                pool.bind_addrs = bind::tcp_addrs(&fds);
+                pool.bind_fds = fds;
+                pool.output = output;
+                dropped
+            };
+            // Children hold their own copies of sockets no longer wanted, so the files stay until
+            // those children are gone
+            state.dropped_socks.extend(bind::unix_paths(&dropped));
+            for fd in dropped {
+                nix::unistd::close(fd).ok();
+            }
+            // Give up slots beyond the new count first, so they aren't upgraded for nothing
+            for (_, o) in brood.iter_mut() {
+                if o.pool == name && o.holds_slot() && o.slot as u64 >= pool_cfg.count {
+                    o.shutdown(state);
+                }
+            }
+            upgrade_pool(brood, state, &name);
+        } else {
+            let log = state.log.new(o!("pool" => name.clone()));
+            info!(log, "adding pool");
+            state.pools.push(new_pool(pool_cfg, fds, output, 0, log));
+        }
+        // Fill any new slots
+        fill_slots(brood, state, &name);
+    }
+    // A dropped socket may have been added back
+    let live: Vec<PathBuf> = state.pools.iter().flat_map(|p| bind::unix_paths(&p.bind_fds)).collect();
+    state.dropped_socks.retain(|path| !live.contains(path));
+    Ok(())
+}
+
+// Spawns children into any of the pool's slots that nobody holds
+fn fill_slots(brood: &mut HashMap<u32, Offspring>, state: &mut EinState, name: &str) {
+    for slot in 0..state.pool(name).cfg.count as usize {
+        if !brood.values().any(|o| o.pool == name && o.holds_slot() && o.slot == slot) {
+            if let Ok(o) = spawn_into(state, name, slot, None, 0) {
+                brood.insert(o.pid, o);
+            }
+        }
+    }
+}
+
+// Spawns a child into the pool's slot, to succeed `replaces` if given. A spawn that fails counts
+// against the retries just like a child that never becomes healthy, and is tried again until those
+// run out; then the slot is left empty, and the last error returned.
+fn spawn_into(state: &mut EinState,
+              name: &str,
+              slot: usize,
+              replaces: Option<u32>,
+              attempts: u64)
+              -> Result<Offspring, String> {
+    let mut attempts = attempts;
+    loop {
+        match Offspring::spawn(state, name, slot) {
+            Ok(mut o) => {
+                o.replaces = replaces;
+                o.attempts = attempts;
+                return Ok(o);
+            }
+            Err(e) => {
+                error!(state.pool(name).log, "couldn't spawn child";
+                    "slot" => slot, "attempt" => attempts + 1, "err" => e.clone());
+                if attempts + 1 >= state.pool(name).cfg.retries {
+                    warn!(state.pool(name).log, "ran out of retries while spawning";
+                        "slot" => slot);
+                    let mut env = pool_env(state.pool(name));
+                    env.push(("EINHORN_CHILD_INDEX".to_string(), slot.to_string()));
+                    run_hooks(state, HookEvent::RetriesExhausted, env);
+                    return Err(e);
+                }
+                attempts += 1;
+            }
+        }
+    }
+}
+
+// Reopens the master's log file and the pools' output files, which logrotate may have moved away
+fn reopen_logs(state: &EinState) {
+    let files = state.log_file.iter().chain(state.pools.iter().filter_map(|p| p.output.as_ref()));
+    for file in files {
+        match file.reopen() {
+            Ok(()) => info!(state.log, "reopened log file"; "path" => file.path()),
+            Err(e) => {
+                warn!(state.log, "couldn't reopen log file";
+                      "path" => file.path(), "err" => e.to_string())
+            }
+        }
+    }
+}
+
+// (Re)starts periodic resource sampling, if any pool has limits to enforce
+fn schedule_sampling(state: &mut EinState) {
+    state.sampler = if state.pools
+        .iter()
+        .any(|p| p.cfg.max_rss_mb.is_some() || p.cfg.max_cpu_secs.is_some()) {
+        let t_tx = state.timer_tx.clone();
+        Some(state.timer.schedule_repeating(state.cfg.resource_interval, move || {
+            t_tx.send(TimerAction::SampleResources);
+        }))
+    } else {
+        None
+    };
+}
+
+// Notices upgrades that have finished: every child of the pool is from the current generation, and
+// healthy (or on its way out).
+fn check_upgrades(brood: &HashMap<u32, Offspring>, state: &mut EinState) {
+    for i in 0..state.pools.len() {
+        let done = {
+            let pool = &state.pools[i];
+            pool.upgrading &&
+            !brood.values().any(|o| {
+                o.pool == pool.cfg.name &&
+                (o.generation < pool.generation || o.state == OffspringState::Infancy)
+            })
+        };
+        if done {
+            state.pools[i].upgrading = false;
+            info!(state.pools[i].log, "upgrade complete");
+            let env = pool_env(&state.pools[i]);
+            run_hooks(state, HookEvent::PostUpgrade, env);
+        }
+    }
+}
+
+// Runs the hooks configured for `event`. Hooks that come before something (or the master
+// exiting) are waited for; the others run in the background.
+fn run_hooks(state: &EinState, event: HookEvent, env: Vec<(String, String)>) {
+    let wait = match event {
+        HookEvent::Start | HookEvent::Exit => true,
+        // Pre-upgrade hooks are waited for in the background instead, see upgrade_pool()
+        HookEvent::PreUpgrade | HookEvent::PostUpgrade | HookEvent::Crash |
+        HookEvent::RetriesExhausted => false,
+    };
+    for &(e, ref cmdline) in &state.cfg.hooks {
+        if e != event {
+            continue;
+        }
+        let timeout = state.cfg.hook_timeout;
+        if wait {
+            hook::run(event, cmdline, &env, timeout, &state.helpers, &state.log);
+        } else {
+            let cmdline = cmdline.clone();
+            let env = env.clone();
+            let helpers = state.helpers.clone();
+            let log = state.log.clone();
+            thread::spawn(move || hook::run(event, &cmdline, &env, timeout, &helpers, &log));
+        }
+    }
+}
+
+fn pool_env(pool: &Pool) -> Vec<(String, String)> {
+    vec![("EINHORN_POOL_NAME".to_string(), pool.cfg.name.clone()),
+         ("EINHORN_GENERATION".to_string(), pool.generation.to_string())]
+}
+
+fn offspring_env(o: &Offspring) -> Vec<(String, String)> {
+    vec![("EINHORN_POOL_NAME".to_string(), o.pool.clone()),
+         ("EINHORN_GENERATION".to_string(), o.generation.to_string()),
+         ("EINHORN_CHILD_PID".to_string(), o.pid.to_string()),
+         ("EINHORN_CHILD_INDEX".to_string(), o.slot.to_string())]
+}
+
+// Shell-style exit status: the exit code, or 128 plus the signal number
+fn exit_status(status: nix::sys::wait::WaitStatus) -> i32 {
+    match status {
+        nix::sys::wait::WaitStatus::Exited(_, code) => code as i32,
+        nix::sys::wait::WaitStatus::Signaled(_, sig, _) => 128 + sig as i32,
+        _ => 1,
+    }
+}
+
+fn exit_env(status: nix::sys::wait::WaitStatus) -> Vec<(String, String)> {
+    match status {
+        nix::sys::wait::WaitStatus::Exited(_, code) => {
+            vec![("EINHORN_EXIT_STATUS".to_string(), code.to_string())]
+        }
+        nix::sys::wait::WaitStatus::Signaled(_, sig, _) => {
+            vec![("EINHORN_EXIT_SIGNAL".to_string(), format!("{:?}", sig))]
+        }
+        _ => vec![],
+    }
+}
+
+// The lowest slot number in the pool no live child is filling
+fn free_slot(brood: &HashMap<u32, Offspring>, pool: &str) -> usize {
+    let mut slot = 0;
+    while brood.values().any(|o| o.pool == pool && o.holds_slot() && o.slot == slot) {
+        slot += 1;
+    }
+    slot
+}
+
+// For each pool, a summary line followed by one line per child, ordered by slot
+fn status_summary(brood: &HashMap<u32, Offspring>, state: &EinState, pools: &[String]) -> String {
+    let now = time::get_time();
+    let mut lines = vec![];
+    if state.draining.is_some() {
+        lines.push("shutting down".to_string());
+    }
+    for name in pools {
+        let pool = state.pool(name);
+        lines.push(format!("pool {}: count: {}, generation: {}",
+                           name,
+                           pool.cfg.count,
+                           pool.generation));
+        let mut children: Vec<&Offspring> = brood.values().filter(|o| o.pool == *name).collect();
+        children.sort_by_key(|o| (o.slot, o.pid));
+        for o in children {
+            lines.push(format!("  slot {}: pid {}, {}, generation {}, up {}s",
+                               o.slot,
+                               o.pid,
+                               o.state_str(),
+                               o.generation,
+                               (now - o.born).num_seconds()));
+        }
+    }
+    lines.join("\n")
+}
+
+// Whether a successor for `pid` has been spawned already
+fn being_replaced(brood: &HashMap<u32, Offspring>, pid: u32) -> bool {
+    brood.values().any(|s| s.replaces == Some(pid) && s.is_active())
+}
+
+// Whether some successor in the pool is still coming up to replace a live child
+fn replacement_underway(brood: &HashMap<u32, Offspring>, pool: &str) -> bool {
+    brood.values().any(|o| {
+        o.pool == pool && o.state == OffspringState::Infancy &&
+        o.replaces.map_or(false, |old| brood.get(&old).map_or(false, |old| old.is_active()))
+    })
+}
+
+fn offspring_probed(brood: &mut HashMap<u32, Offspring>, state: &mut EinState, res: ProbeResult) {
+    let pid = res.pid;
+    let (o_state, failures) = match brood.get_mut(&pid) {
+        Some(o) => {
+            match res.result {
+                Ok(()) => {
+                    debug!(o.log, "probe passed");
+                    o.probe_failures = 0;
+                }
+                Err(ref e) => {
+                    o.probe_failures += 1;
+                    warn!(o.log, "probe failed";
+                        "err" => e.clone(),
+                        "failures" => o.probe_failures);
+                }
+            }
+            (o.state, o.probe_failures)
+        }
+        None => return,
+    };
+    let given_up = failures >= brood[&pid].cfg(state).probe_failures;
+    match (o_state, res.result.is_ok()) {
+        (OffspringState::Infancy, true) => offspring_healthy(brood, state, pid),
+        (OffspringState::Infancy, false) if given_up => offspring_unhealthy(brood, state, pid),
+        (OffspringState::Healthy, false) if given_up => {
+            brood.get_mut(&pid).unwrap().probe_guard = None;
+            offspring_replace(brood, state, pid, "failed liveness probes");
+        }
+        (OffspringState::Infancy, false) |
+        (OffspringState::Healthy, _) => brood.get_mut(&pid).unwrap().schedule_probe(state),
+        // Shutting down; stop probing
+        _ => (),
+    }
 }
 
 // * * * * * * *   Setup and CLI   * * * * * * *
 
 fn print_usage(opts: Options) {
-    let brief = "usage:\teinhyrningsins [options] [--] program [program_args]";
+    let brief = "usage:\teinhyrningsins [options] [--] program [program_args]\n\
+                 \teinhyrningsins [options] --pool NAME [options] [--] program [program_args] \
+                 [--pool ...]";
     println!("");
     print!("{}", opts.usage(&brief));
 }
 
-fn main() {
-
-    let args: Vec<String> = env::args().collect();
-
-    let mut opts = Options::new();
-    opts.parsing_style(getopts::ParsingStyle::StopAtFirstFree);
-    opts.optflag("h", "help", "print this help menu");
-    opts.optflag("", "version", "print the version");
-    opts.optflag("v", "verbose", "more debugging messages");
-    opts.optflag("", "syslog", "enables syslog-ing (for WARN and above)");
-    opts.optflag("4", "ipv4-only", "only accept IPv4 connections");
-    opts.optflag("6", "ipv6-only", "only accept IPv6 connections");
-    opts.optflag("m", "manual", "manual (explicit) acknowledge mode");
-    opts.optopt("n", "number", "how many program copies to spawn", "COUNT");
-    opts.optmulti("b", "bind", "socket(s) to bind to (can be repeated)", "ADDR");
-    opts.optmulti("", "drop-env-var", "ENV variables to mask (can be repeated)", "VAR");
-    opts.optopt("d", "socket-path", "where to create the control socket (default: /tmp/einhorn.sock)", "PATH");
-    opts.optopt("r", "retries", "how many times to attempt spawning", "COUNT");
-
-    let matches = match opts.parse(&args[1..]) {
+fn parse_args_or_exit(opts: &Options, args: &[String]) -> getopts::Matches {
+    match opts.parse(args) {
         Ok(m) => m,
         Err(f) => {
             println!("{}\n", f.to_string());
-            print_usage(opts);
+            print_usage(ein_options());
             exit(-1);
         }
-    };
+    }
+}
+
+fn main() {
+
+    let args: Vec<String> = env::args().collect();
+    let opts = ein_options();
+
+    // Only a first look at the global options, for --help and the like; the real parsing
+    // happens in config::load()
+    let leading_end = args.iter().skip(1).position(|a| a == "--pool").map_or(args.len(), |i| i + 1);
+    let matches = parse_args_or_exit(&opts, &args[1..leading_end]);
 
     if matches.opt_present("help") {
         print_usage(opts);
@@ -511,67 +2048,146 @@ fn main() {
         return;
     }
 
-    if matches.opt_present("4") && matches.opt_present("6") {
-        println!("Can't be both IPv4-only and IPv6-only");
-        exit(-1);
-    }
-
     /// Parse Configuration
-    let path_str = matches.opt_str("socket-path").unwrap_or("/tmp/einhorn.sock".to_string());
-
-    let count = match matches.opt_str("number") {
-        Some(n) => u64::from_str(&n).expect("number arg should be an integer"),
-        None => 1,
+    let mut cfg = match config::load(&args[1..]) {
+        Ok(cfg) => cfg,
+        Err(e) => {
+            println!("{}", e);
+            exit(-1);
+        }
     };
 
-    let retries = match matches.opt_str("retries") {
-        Some(n) => u64::from_str(&n).expect("retries arg should be an integer"),
-        None => 1,
-    };
+    // Otherwise, a bad placeholder would only show up once the first children are spawned
+    for pool in &cfg.pools {
+        if let Err(e) = check_templates(pool) {
+            println!("{}", e);
+            exit(-1);
+        }
+    }
+
+    if matches.opt_present("check-config") {
+        match check_config(&cfg) {
+            Ok(()) => {
+                println!("Config OK");
+                return;
+            }
+            Err(e) => {
+                println!("{}", e);
+                exit(-1);
+            }
+        }
+    }
 
-    let bind_slugs = matches.opt_strs("bind");
-    let env_drops = matches.opt_strs("drop-env-var");
-    let ipv4_only = matches.opt_present("4");
-    let ipv6_only = matches.opt_present("6");
-    let manual_ack = matches.opt_present("m");
-    let verbose = matches.opt_present("verbose");
-    let syslog = matches.opt_present("syslog");
+    // A re-exec'd master inherits sockets, children and runtime changes from its predecessor
+    let resumed = match env::var("EINHYRNINGSINS_STATE") {
+        Ok(saved) => {
+            env::remove_var("EINHYRNINGSINS_STATE");
+            match json::parse(&saved) {
+                Ok(r) => Some(r),
+                Err(e) => {
+                    println!("Couldn't parse state inherited from previous master: {}", e);
+                    exit(-1);
+                }
+            }
+        }
+        Err(_) => None,
+    };
+    // Before any change of directory
+    let exe = absolute_exe(&args[0]);
 
-    let program_and_args = if !matches.free.is_empty() {
-        matches.free
+    // A re-exec'd master is already in the background. Nothing before this point may start
+    // threads.
+    let readiness = if cfg.daemonize && resumed.is_none() {
+        match daemon::daemonize() {
+            Ok(readiness) => Some(readiness),
+            Err(e) => {
+                println!("{}", e);
+                exit(-1);
+            }
+        }
     } else {
-        println!("Missing program to run (try --help)");
-        exit(-1);
+        None
     };
-    let mut program_and_args = program_and_args.into_iter();
-    let cfg = EinConfig {
-        program: program_and_args.next().unwrap(),
-        program_args: program_and_args.collect(),
-        count: count,
-        childhood: Duration::seconds(3),
-        graceperiod: Duration::seconds(3),
-        retries: retries,
-        ipv4_only: ipv4_only,
-        ipv6_only: ipv6_only,
-        manual_ack: manual_ack,
-        ctrl_path: path_str,
-        bind_slugs: bind_slugs,
-        env_drops: env_drops,
-        verbose: verbose,
-        syslog: syslog,
+    if let Some(ref dir) = cfg.working_dir {
+        if let Err(e) = env::set_current_dir(dir) {
+            println!("Couldn't change to working directory {}: {}", dir, e);
+            exit(-1);
+        }
+    }
+
+    // With --adopt, children left running by a crashed master are taken over
+    let crashed = match cfg.state_file {
+        Some(ref path) if cfg.adopt && resumed.is_none() => load_survivors(path),
+        _ => None,
     };
+    if let Some(r) = resumed.as_ref().or(crashed.as_ref()) {
+        for p in r["pools"].members() {
+            let pool = cfg.pools.iter_mut().find(|pool| p["name"].as_str() == Some(&pool.name));
+            if let (Some(pool), Some(count)) = (pool, p["count"].as_u64()) {
+                pool.count = count;
+            }
+        }
+    }
 
-    // Control socket first; not same scope as other state
-    // XXX: handle this more gracefully (per-process)
+    // Only a stale control socket (left behind by a master that crashed) may be removed. This
+    // comes before the pid file, so a master refused here leaves the running one's pid file alone.
     let tmp = cfg.ctrl_path.clone();
     let ctrl_path = Path::new(&tmp);
-    if ctrl_path.exists() {
-        fs::remove_file(&ctrl_path).unwrap();
+    let inherits_ctrl = resumed.as_ref().map_or(false, |r| r["ctrl_fd"].as_i32().is_some());
+    if !inherits_ctrl && ctrl_path.exists() {
+        match ctrl_socket_answers(ctrl_path) {
+            Some(true) => {
+                println!("Another master is already running on control socket {:?}",
+                         ctrl_path);
+                exit(-1);
+            }
+            Some(false) => {
+                println!("Control socket {:?} is in use (but didn't answer ehlo)", ctrl_path);
+                exit(-1);
+            }
+            None => fs::remove_file(&ctrl_path).unwrap(),
+        }
     }
 
-    println!("Binding control socket to: {:?}", ctrl_path);
-    let ctrl_listener = UnixListener::bind(ctrl_path).unwrap();
-    // XXX: set mode/permissions/owner?
+    // The pid file is locked for as long as we run (a re-exec'd master takes it over from
+    // itself). When daemonizing, it's only written once we're up; see check_ready().
+    let pid_file = match cfg.pid_file {
+        Some(ref path) => {
+            let mut pid_file = match PidFile::lock(path) {
+                Ok(p) => p,
+                Err(e) => {
+                    println!("{}", e);
+                    exit(-1);
+                }
+            };
+            let written = if readiness.is_none() {
+                pid_file.write(nix::unistd::getpid() as u32)
+            } else {
+                Ok(())
+            };
+            if let Err(e) = written {
+                println!("Couldn't write pid file {}: {}", path, e);
+                exit(-1);
+            }
+            Some(pid_file)
+        }
+        None => None,
+    };
+
+    // Control socket first; not same scope as other state
+    let ctrl_listener = match resumed.as_ref().and_then(|r| r["ctrl_fd"].as_i32()) {
+        Some(fd) => {
+            println!("Inherited control socket: {:?}", ctrl_path);
+            set_cloexec(fd, true).unwrap();
+            unsafe { UnixListener::from_raw_fd(fd) }
+        }
+        None => {
+            println!("Binding control socket to: {:?}", ctrl_path);
+            UnixListener::bind(ctrl_path).unwrap()
+            // XXX: set mode/permissions/owner?
+        }
+    };
+    let ctrl_fd = ctrl_listener.as_raw_fd();
 
     let (ctrl_req_tx, ctrl_req_rx): (Sender<CtrlRequest>, Receiver<CtrlRequest>) = chan::async();
 
@@ -591,34 +2207,92 @@ fn main() {
                                           Signal::STOP,
                                           Signal::CONT]);
 
-    let state = match init(cfg, ctrl_req_rx) {
+    let mut state = match init(cfg, ctrl_req_rx, ctrl_fd, resumed.as_ref(), crashed.as_ref()) {
         Ok(s) => s,
         Err(e) => {
             println!("{}", e);
             exit(-1);
         }
     };
+    // Last chance to report errors on the terminal; children mustn't inherit it
+    if readiness.is_some() {
+        if let Err(e) = daemon::detach_stdio(state.cfg.log_file.as_ref().map(|p| &p[..])) {
+            println!("{}", e);
+            exit(-1);
+        }
+    }
+    state.pid_file = pid_file;
+    state.readiness = readiness;
+    state.exe = exe;
 
     /// Start Constrol Socket Thread
     let ctrl_log = state.log.clone();
     thread::spawn(move || ctrl_socket_serve(ctrl_listener, ctrl_req_tx, ctrl_log));
 
     /// State Event Loop
-    shepard(state, signal_rx);
-    exit(0);
+    let code = shepard(state, signal_rx, resumed, crashed);
+    exit(code);
+}
+
+// A relative argv[0] (like "./target/einhyrningsins") made absolute, so re-exec still finds it
+// after --working-dir. Bare names are left for execvp(3) to look up in PATH, and symlinks aren't
+// resolved, so re-exec picks up a new binary installed behind the same path.
+fn absolute_exe(argv0: &str) -> Option<String> {
+    let path = Path::new(argv0);
+    if path.is_absolute() || !argv0.contains('/') {
+        return None;
+    }
+    env::current_dir().ok().map(|dir| dir.join(path).to_string_lossy().into_owned())
+}
+
+// Checks for a master on the control socket at `path`: None if nothing is listening there, else
+// whether it answered an ehlo.
+fn ctrl_socket_answers(path: &Path) -> Option<bool> {
+    let stream = match UnixStream::connect(path) {
+        Ok(stream) => stream,
+        Err(_) => return None,
+    };
+    stream.set_read_timeout(Some(std::time::Duration::from_secs(2))).ok();
+    let mut reply = String::new();
+    let answered = (&stream)
+        .write_all(b"{\"command\": \"ehlo\", \"args\": []}\n")
+        .and_then(|_| BufReader::new(&stream).read_line(&mut reply))
+        .map(|n| n > 0)
+        .unwrap_or(false);
+    Some(answered)
 }
 
 // Initializes config into state
-fn init(cfg: EinConfig, ctrl_req_rx: Receiver<CtrlRequest>) -> Result<EinState, String> {
+fn init(cfg: EinConfig,
+        ctrl_req_rx: Receiver<CtrlRequest>,
+        ctrl_fd: RawFd,
+        resumed: Option<&json::JsonValue>,
+        crashed: Option<&json::JsonValue>)
+        -> Result<EinState, String> {
 
     /// Configure logging
-    let term_drain =
-        slog::level_filter(if cfg.verbose {
-                               slog::Level::Debug
-                           } else {
-                               slog::Level::Info
-                           },
-                           slog_term::streamer().async().auto_color().compact().build());
+    let log_file = match cfg.log_file {
+        Some(ref path) => {
+            Some(Arc::new(try!(output::OutputFile::open(path)
+                .map_err(|e| format!("Couldn't open log file {}: {}", path, e)))))
+        }
+        None => None,
+    };
+    let main_drain: Box<slog::Drain<Error = std::io::Error> + Send + Sync> = match log_file {
+        Some(ref file) => {
+            let format = slog_term::Format::new(slog_term::FormatMode::Compact,
+                                                slog_term::ColorDecorator::new_plain(),
+                                                Box::new(slog_term::timestamp_local));
+            Box::new(slog_stream::async_stream(output::Writer(file.clone()), format))
+        }
+        None => slog_term::streamer().async().auto_color().compact().build(),
+    };
+    let term_drain = slog::level_filter(if cfg.verbose {
+                                            slog::Level::Debug
+                                        } else {
+                                            slog::Level::Info
+                                        },
+                                        main_drain);
     let syslog_drain =
         slog::level_filter(slog::Level::Warning,
                            slog_syslog::unix_3164(slog_syslog::Facility::LOG_DAEMON));
@@ -626,122 +2300,187 @@ fn init(cfg: EinConfig, ctrl_req_rx: Receiver<CtrlRequest>) -> Result<EinState,
     let log_root = slog::Logger::root(slog::duplicate(term_drain, syslog_drain).ignore_err(),
                                       o!("version" => env!("CARGO_PKG_VERSION")));
 
-    // These will be tuples: (SocketAddr, SO_REUSEADDR, O_NONBLOCK)
-    let sock_confs: Vec<(SocketAddr, bool, bool)> = cfg.bind_slugs
-        .iter()
-        .map(|b| {
-            let mut r = false;
-            let mut n = false;
-            let mut addr_chunks = b.split(',');
-            let sock_str = addr_chunks.next().unwrap(); // safe
-            let mut sock_addrs = sock_str.to_socket_addrs().unwrap();
-            // ugly
-            let sock = if cfg.ipv4_only {
-                let mut sock_addrs = sock_addrs.filter(|sa| if let SocketAddr::V4(_) = *sa {
-                    true
-                } else {
-                    false
-                });
-                sock_addrs.next().expect("Couldn't bind as IPv4")
-            } else if cfg.ipv6_only {
-                let mut sock_addrs = sock_addrs.filter(|sa| if let SocketAddr::V6(_) = *sa {
-                    true
-                } else {
-                    false
-                });
-                sock_addrs.next().expect("Couldn't bind as IPv6")
-            } else {
-                sock_addrs.next().expect("Couldn't bind socket")
-            };
-            for subarg in addr_chunks {
-                match subarg {
-                    "r" => r = true,
-                    "n" => n = true,
-                    "" => (),
-                    _ => {
-                        println!("Unknown socket arg '{}', I only know about 'n' and 'r'. Try \
-                                  --help",
-                                 subarg);
-                        exit(-1);
-                    }
-                }
+    let mut pools = vec![];
+    for pool_cfg in &cfg.pools {
+        let saved_pool = |saved: &json::JsonValue| {
+            saved["pools"].members().find(|p| p["name"].as_str() == Some(&pool_cfg.name)).cloned()
+        };
+        let saved = resumed.and_then(&saved_pool);
+        let bind_fds: Vec<RawFd> = match saved {
+            Some(ref p) => p["bind_fds"].members().filter_map(|fd| fd.as_i32()).collect(),
+            None => {
+                let donors = crashed.map_or(vec![], |c| socket_donors(c, &pool_cfg.name));
+                try!(bind::bind_all(pool_cfg, &donors, &log_root))
             }
-            (sock, r, n)
-        })
-        .collect();
-
-    let binds: Vec<(TcpListener, bool, bool)> = sock_confs.iter()
-        .map(|t| {
-            let sa = t.0;
-            let r = t.1;
-            let n = t.2; // ugly
-            (TcpListener::bind(sa).unwrap(), r, n)
-        })
-        .collect();
-
-    let mut cmd = Command::new(cfg.program.clone());
-    cmd.args(&cfg.program_args);
-    for var in &cfg.env_drops {
-        cmd.env_remove(var);
+        };
+        if let Some(ref p) = pool_cfg.probe {
+            if p.needs_default_addr() && bind::tcp_addrs(&bind_fds).is_empty() {
+                return Err("Probe needs an address if there are no bound TCP sockets".to_string());
+            }
+        }
+        // Only worth cluttering the logs with pool names if there's more than one
+        let log = if cfg.pools.len() > 1 {
+            log_root.new(o!("pool" => pool_cfg.name.clone()))
+        } else {
+            log_root.clone()
+        };
+        let output = try!(open_output_file(pool_cfg));
+        let generation = saved.or(crashed.and_then(&saved_pool))
+            .and_then(|p| p["generation"].as_u64())
+            .unwrap_or(0);
+        pools.push(new_pool(pool_cfg, bind_fds, output, generation, log));
     }
 
-    let bind_fds: Vec<RawFd> = binds.into_iter()
-        .map(|t| {
-            let b = t.0;
-            let r = t.1;
-            let n = t.2;  // ugly
-            let orig_fd = b.into_raw_fd();
-            // Duplicate, which also clears the CLOEXEC flag
-            let fd = nix::unistd::dup(orig_fd).unwrap();
-            if r {
-                nix::sys::socket::setsockopt(fd, nix::sys::socket::sockopt::ReuseAddr, &true)
-                    .unwrap();
-            }
-            if n {
-                nix::fcntl::fcntl(fd, nix::fcntl::FcntlArg::F_SETFL(nix::fcntl::O_NONBLOCK))
-                    .unwrap();
-            }
-            debug!(log_root, "bound socket";
-            "fd" => fd,
-            "FD_CLOEXEC" => nix::fcntl::fcntl(fd, nix::fcntl::FcntlArg::F_GETFD).unwrap());
-            fd
-        })
-        .collect();
-
-    cmd.env("EINHORN_FD_COUNT", bind_fds.len().to_string());
-    // This iterator destroys the TcpListeners
-    for (i, fd) in bind_fds.iter().enumerate() {
-        cmd.env(format!("EINHORN_FD_{}", i), fd.to_string());
+    if cfg.subreaper {
+        if unsafe { libc::prctl(libc::PR_SET_CHILD_SUBREAPER, 1 as libc::c_ulong) } != 0 {
+            return Err(format!("Couldn't become a subreaper: {}", std::io::Error::last_os_error()));
+        }
     }
-    cmd.env("EINHORN_SOCK_PATH", cfg.ctrl_path.clone());
 
     // create timer thread
     let timer = timer::Timer::new();
     let (timer_tx, timer_rx): (Sender<TimerAction>, Receiver<TimerAction>) = chan::async();
+    let (probe_tx, probe_rx): (Sender<ProbeResult>, Receiver<ProbeResult>) = chan::async();
 
     Ok(EinState {
-        cmd: cmd,
         ctrl_req_rx: ctrl_req_rx,
         cfg: cfg,
         timer: timer,
         timer_tx: timer_tx,
         timer_rx: timer_rx,
+        probe_tx: probe_tx,
+        probe_rx: probe_rx,
+        helpers: Helpers::new(),
+        pools: pools,
+        ctrl_fd: ctrl_fd,
+        draining: None,
+        sampler: None,
+        log_file: log_file,
+        pid_file: None,
+        readiness: None,
+        exe: None,
+        dropped_socks: vec![],
         log: log_root,
     })
 }
 
+fn new_pool(cfg: &PoolConfig,
+            bind_fds: Vec<RawFd>,
+            output: Option<Arc<output::OutputFile>>,
+            generation: u64,
+            log: slog::Logger)
+            -> Pool {
+    Pool {
+        cfg: cfg.clone(),
+        generation: generation,
+        upgrading: false,
+        bind_addrs: bind::tcp_addrs(&bind_fds),
+        bind_fds: bind_fds,
+        output: output,
+        log: log,
+    }
+}
+
+fn open_output_file(cfg: &PoolConfig) -> Result<Option<Arc<output::OutputFile>>, String> {
+    match cfg.output_file {
+        Some(ref path) => {
+            output::OutputFile::open(path)
+                .map(|f| Some(Arc::new(f)))
+                .map_err(|e| format!("Couldn't open output file {}: {}", path, e))
+        }
+        None => Ok(None),
+    }
+}
+
+// Checks everything that can be checked without actually starting up (for --check-config)
+fn check_config(cfg: &EinConfig) -> Result<(), String> {
+    for pool in &cfg.pools {
+        let binds = try!(bind::resolve(pool));
+        if let Some(ref p) = pool.probe {
+            if p.needs_default_addr() && !binds.iter().any(|b| b.addr.is_tcp()) {
+                return Err("Probe needs an address if there are no bound TCP sockets".to_string());
+            }
+        }
+        try!(check_templates(pool));
+        try!(check_program(&pool.program));
+    }
+    Ok(())
+}
+
+// Looks for the program the way exec does (on PATH, unless the name has a slash in it), so that a
+// typo is caught before any child is replaced with it
+fn check_program(program: &str) -> Result<(), String> {
+    let runnable = |path: &Path| {
+        fs::metadata(path)
+            .map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+            .unwrap_or(false)
+    };
+    let found = if program.contains('/') {
+        runnable(Path::new(program))
+    } else {
+        env::var_os("PATH").map_or(false, |paths| {
+            env::split_paths(&paths).any(|dir| runnable(&dir.join(program)))
+        })
+    };
+    if found {
+        Ok(())
+    } else {
+        Err(format!("Can't run '{}': not found, or not executable", program))
+    }
+}
+
+// Tries filling in the placeholders for each of the children spawned at startup
+fn check_templates(pool: &PoolConfig) -> Result<(), String> {
+    for slot in 0..pool.count as usize {
+        let vars = template::Vars {
+            slot: slot,
+            generation: 0,
+            master_pid: 0,
+        };
+        for s in pool.program_args.iter().chain(pool.env_sets.iter().map(|&(_, ref v)| v)) {
+            try!(template::expand(s, &vars));
+        }
+        if let Some(ref p) = pool.probe {
+            try!(p.expand(&vars));
+        }
+    }
+    Ok(())
+}
+
+// Survivors of a crashed master holding a pool's sockets, as (pid, fd) pairs. Children inherit the
+// sockets at the master's own fd numbers.
+fn socket_donors(crashed: &json::JsonValue, pool: &str) -> Vec<(u32, RawFd)> {
+    let fds: Vec<RawFd> = crashed["pools"]
+        .members()
+        .filter(|p| p["name"].as_str() == Some(pool))
+        .flat_map(|p| p["bind_fds"].members())
+        .filter_map(|fd| fd.as_i32())
+        .collect();
+    crashed["children"]
+        .members()
+        .filter(|c| c["pool"].as_str() == Some(pool))
+        .filter_map(|c| c["pid"].as_u32())
+        .flat_map(|pid| fds.iter().map(move |&fd| (pid, fd)))
+        .collect()
+}
+
 // * * * * * * *   Control Socket Server   * * * * * * *
 
 const CTRL_SHELL_USAGE: &'static str = r#"Command Listing:
 
-    inc             increments number of children
-    dec             decrements number of children
-    upgrade         replaces all children with new spawns, gracefully
-    die             kills all children gracefully, then exits
-    signal SIG      sends signal SIG to all children
-    status          shows summary state of children
-    help            prints this help message
-    version         prints (master) version
+    inc [POOL]          increments number of children
+    dec [POOL]          decrements number of children
+    upgrade [POOL]      replaces all children with new spawns, gracefully
+    reload              re-reads the config, then upgrades all children with it
+    reopen-logs         reopens log files (after they've been rotated)
+    reexec              replaces the master process itself (keeping sockets and children)
+    die                 kills all children gracefully, then exits
+    signal SIG [POOL]   sends signal SIG to all children
+    status [POOL]       shows summary state of children
+    help                prints this help message
+    version             prints (master) version
+
+With more than one pool, inc and dec need a POOL; the others default to all pools.
 "#;
 
 fn ctrl_socket_handle(stream: UnixStream, ctrl_req_tx: Sender<CtrlRequest>, log: slog::Logger) {
@@ -756,22 +2495,17 @@ fn ctrl_socket_handle(stream: UnixStream, ctrl_req_tx: Sender<CtrlRequest>, log:
         }
 
         // Parse message
-        let req_action = if let Ok(msg) = json::parse(&rawline) {
-            match msg["command"].as_str() {
+        let (req_action, req_pool) = if let Ok(msg) = json::parse(&rawline) {
+            // The pool name is the last argument, if any (or an explicit "pool" field)
+            let pool_arg = if msg["command"].as_str() == Some("signal") { 1 } else { 0 };
+            let pool = msg["pool"].as_str().or(msg["args"][pool_arg].as_str()).map(String::from);
+            let action = match msg["command"].as_str() {
                 Some("worker:ack") => CtrlAction::ManualAck(msg["pid"].as_u32().unwrap()),
+                Some("worker:heartbeat") => CtrlAction::Heartbeat(msg["pid"].as_u32().unwrap()),
                 Some("signal") => {
-                    CtrlAction::SigAll(match msg["args"][0].as_str() {
-                        Some("SIGHUP")  | Some("HUP")  | Some("hup")  => Signal::HUP,
-                        Some("SIGINT")  | Some("INT")  | Some("int")  => Signal::INT,
-                        Some("SIGTERM") | Some("TERM") | Some("term") => Signal::TERM,
-                        Some("SIGTTIN") | Some("TTIN") | Some("ttin") => Signal::TTIN,
-                        Some("SIGTTOU") | Some("TTOU") | Some("ttou") => Signal::TTOU,
-                        Some("SIGKILL") | Some("KILL") | Some("kill") => Signal::KILL,
-                        Some("SIGUSR1") | Some("USR1") | Some("usr1") => Signal::USR1,
-                        Some("SIGUSR2") | Some("USR2") | Some("usr2") => Signal::USR2,
-                        Some("SIGSTOP") | Some("STOP") | Some("stop") => Signal::STOP,
-                        Some("SIGCONT") | Some("CONT") | Some("cont") => Signal::CONT,
-                        Some(_) | None => {
+                    CtrlAction::SigAll(match msg["args"][0].as_str().and_then(signal_from_str) {
+                        Some(sig) => sig,
+                        None => {
                             writer.write_all(b"\"Missing or unhandled 'signal'\"\n").unwrap();
                             writer.flush().unwrap();
                             continue;
@@ -783,6 +2517,9 @@ fn ctrl_socket_handle(stream: UnixStream, ctrl_req_tx: Sender<CtrlRequest>, log:
                 Some("status") => CtrlAction::Status,
                 Some("die") => CtrlAction::ShutdownAll,
                 Some("upgrade") => CtrlAction::UpgradeAll,
+                Some("reload") => CtrlAction::Reload,
+                Some("reopen-logs") => CtrlAction::ReopenLogs,
+                Some("reexec") => CtrlAction::ReExec,
                 Some("ehlo") => {
                     writer.write_all(b"\"Hi there!\"\n\r").unwrap();
                     writer.flush().unwrap();
@@ -806,7 +2543,8 @@ fn ctrl_socket_handle(stream: UnixStream, ctrl_req_tx: Sender<CtrlRequest>, log:
                     writer.flush().unwrap();
                     continue;
                 }
-            }
+            };
+            (action, pool)
         } else {
             writer.write_all(b"\"Expected valid JSON!\"\n").unwrap();
             writer.flush().unwrap();
@@ -817,15 +2555,15 @@ fn ctrl_socket_handle(stream: UnixStream, ctrl_req_tx: Sender<CtrlRequest>, log:
         let (tx, rx): (Sender<String>, Receiver<String>) = chan::async();
         let req = CtrlRequest {
             action: req_action,
+            pool: req_pool,
             tx: tx,
         };
         ctrl_req_tx.send(req);
 
-        // Send reply
-        let resp = rx.recv().unwrap();
-        writer.write_all(b"\"").unwrap();
+        // Send reply (may span multiple lines, so escape it)
+        let resp = json::stringify(json::JsonValue::from(rx.recv().unwrap()));
         writer.write_all(resp.as_bytes()).unwrap();
-        writer.write_all(b"\"\n").unwrap();
+        writer.write_all(b"\n").unwrap();
         writer.flush().unwrap();
     }
     stream.shutdown(std::net::Shutdown::Both).unwrap();
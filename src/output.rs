@@ -0,0 +1,137 @@
+//  einhyrningsins: graceful restarts for socket-based daemons
+//  Copyright (C) 2016  Bryan Newbold <bnewbold@robocracy.org>
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU General Public License as published by
+//  the Free Software Foundation, either version 3 of the License, or
+//  (at your option) any later version.
+//
+//  This program is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//  GNU General Public License for more details.
+//
+//  You should have received a copy of the GNU General Public License
+//  along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+// Captured child output. Each child's stdout and stderr are pipes, read line by line on threads
+// of their own (so a chatty child never holds up the main loop), and each line is either logged
+// or appended to the pool's output file, tagged with the child it came from.
+//
+// Output files (and the master's own log file) can be reopened, for log rotation.
+
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::fs::{File, OpenOptions};
+use std::os::unix::io::{RawFd, FromRawFd};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use time;
+use slog;
+
+// Longer lines are split into chunks of this many bytes
+const MAX_LINE: u64 = 16 * 1024;
+
+pub struct OutputFile {
+    path: String,
+    file: Mutex<File>,
+}
+
+impl OutputFile {
+    pub fn open(path: &str) -> io::Result<OutputFile> {
+        Ok(OutputFile {
+            path: path.to_string(),
+            file: Mutex::new(try!(OpenOptions::new().append(true).create(true).open(path))),
+        })
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    // Swaps in a fresh handle on the same path, which may have been moved away
+    pub fn reopen(&self) -> io::Result<()> {
+        let fresh = try!(OpenOptions::new().append(true).create(true).open(&self.path));
+        *self.file.lock().unwrap() = fresh;
+        Ok(())
+    }
+
+    fn write_line(&self, line: &str) {
+        let mut file = self.file.lock().unwrap();
+        // Nowhere better to report failures; the line is dropped
+        file.write_all(line.as_bytes()).ok();
+    }
+}
+
+// A shared handle on an output file, for use as a log stream
+pub struct Writer(pub Arc<OutputFile>);
+
+impl Write for Writer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.file.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.file.lock().unwrap().flush()
+    }
+}
+
+// One captured stream of a child
+pub struct Capture {
+    pub fd: RawFd,
+    pub stream: &'static str,
+    open: Arc<AtomicBool>,
+}
+
+impl Capture {
+    // Whether the reading thread still has the pipe open
+    pub fn is_open(&self) -> bool {
+        self.open.load(Ordering::SeqCst)
+    }
+}
+
+// Starts reading lines from the (read end of the) pipe `fd`, which the reading thread takes
+// ownership of. `tag` identifies the child in the output file.
+pub fn capture(fd: RawFd,
+               stream: &'static str,
+               sink: Option<Arc<OutputFile>>,
+               tag: String,
+               log: slog::Logger)
+               -> Capture {
+    let open = Arc::new(AtomicBool::new(true));
+    let still_open = open.clone();
+    thread::spawn(move || {
+        let mut reader = BufReader::new(unsafe { File::from_raw_fd(fd) });
+        let mut buf = vec![];
+        loop {
+            buf.clear();
+            match (&mut reader).take(MAX_LINE).read_until(b'\n', &mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => (),
+            }
+            if buf.last() == Some(&b'\n') {
+                buf.pop();
+            }
+            let line = String::from_utf8_lossy(&buf);
+            match sink {
+                Some(ref file) => {
+                    file.write_line(&format!("{} {} {}: {}\n",
+                                             time::now().rfc3339(),
+                                             tag,
+                                             stream,
+                                             line))
+                }
+                None => info!(log, "child output"; "stream" => stream, "line" => line.into_owned()),
+            }
+        }
+        still_open.store(false, Ordering::SeqCst);
+    });
+    Capture {
+        fd: fd,
+        stream: stream,
+        open: open,
+    }
+}
@@ -0,0 +1,68 @@
+//  einhyrningsins: graceful restarts for socket-based daemons
+//  Copyright (C) 2016  Bryan Newbold <bnewbold@robocracy.org>
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU General Public License as published by
+//  the Free Software Foundation, either version 3 of the License, or
+//  (at your option) any later version.
+//
+//  This program is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//  GNU General Public License for more details.
+//
+//  You should have received a copy of the GNU General Public License
+//  along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+// Copying a file descriptor out of another process (Linux 5.6 and up), which is how a master
+// adopting the children of a crashed predecessor gets hold of the listening sockets they still
+// hold. See pidfd_open(2) and pidfd_getfd(2).
+
+use std::io;
+use std::os::unix::io::RawFd;
+use libc;
+
+// Not in our version of libc. Newer syscalls have the same numbers on most architectures, but not
+// all of them (eg, MIPS and Alpha), so only those checked are allowed through.
+#[cfg(any(target_arch = "x86_64", target_arch = "x86", target_arch = "aarch64",
+          target_arch = "arm"))]
+const SYS_PIDFD_OPEN: libc::c_long = 434;
+#[cfg(any(target_arch = "x86_64", target_arch = "x86", target_arch = "aarch64",
+          target_arch = "arm"))]
+const SYS_PIDFD_GETFD: libc::c_long = 438;
+#[cfg(not(any(target_arch = "x86_64", target_arch = "x86", target_arch = "aarch64",
+              target_arch = "arm")))]
+compile_error!("pidfd_open(2) and pidfd_getfd(2) syscall numbers unknown for this architecture");
+
+// Returns our own (close-on-exec) copy of file descriptor `fd` of process `pid`
+pub fn getfd(pid: u32, fd: RawFd) -> Result<RawFd, String> {
+    let pidfd = unsafe { libc::syscall(SYS_PIDFD_OPEN, pid as libc::pid_t, 0 as libc::c_uint) };
+    if pidfd < 0 {
+        return Err(explain("pidfd_open", io::Error::last_os_error(), pid));
+    }
+    let ours = unsafe {
+        libc::syscall(SYS_PIDFD_GETFD, pidfd as libc::c_int, fd, 0 as libc::c_uint)
+    };
+    let err = io::Error::last_os_error();
+    unsafe { libc::close(pidfd as libc::c_int) };
+    if ours < 0 {
+        return Err(explain("pidfd_getfd", err, pid));
+    }
+    Ok(ours as RawFd)
+}
+
+// The usual reasons this doesn't work, spelled out
+fn explain(call: &str, err: io::Error, pid: u32) -> String {
+    match err.raw_os_error() {
+        Some(libc::EPERM) => {
+            format!("{}: {} (needs permission to ptrace(2) pid {}; with Yama, that means \
+                     kernel.yama.ptrace_scope=0 or CAP_SYS_PTRACE)",
+                    call,
+                    err,
+                    pid)
+        }
+        Some(libc::ENOSYS) => format!("{}: {} (needs Linux 5.6 or later)", call, err),
+        _ => format!("{}: {}", call, err),
+    }
+}
@@ -0,0 +1,74 @@
+//  einhyrningsins: graceful restarts for socket-based daemons
+//  Copyright (C) 2016  Bryan Newbold <bnewbold@robocracy.org>
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU General Public License as published by
+//  the Free Software Foundation, either version 3 of the License, or
+//  (at your option) any later version.
+//
+//  This program is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//  GNU General Public License for more details.
+//
+//  You should have received a copy of the GNU General Public License
+//  along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+// The master's pid file. It is held with an exclusive flock(2) for as long as the master runs, so
+// a second master using the same file notices the first, and a file left behind by a crashed
+// master (whose lock went with it) is simply taken over.
+
+use std::io;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::os::unix::io::AsRawFd;
+use libc;
+
+pub struct PidFile {
+    path: String,
+    file: File,
+}
+
+impl PidFile {
+    // Opens and locks the file, without writing anything to it yet
+    pub fn lock(path: &str) -> Result<PidFile, String> {
+        let mut file = try!(OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(path)
+            .map_err(|e| format!("Couldn't open pid file {}: {}", path, e)));
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() == Some(libc::EWOULDBLOCK) {
+                let mut pid = String::new();
+                file.read_to_string(&mut pid).ok();
+                return Err(format!("Another master (pid {}) holds the pid file {}",
+                                   pid.trim(),
+                                   path));
+            }
+            return Err(format!("Couldn't lock pid file {}: {}", path, err));
+        }
+        Ok(PidFile {
+            path: path.to_string(),
+            file: file,
+        })
+    }
+
+    // Replaces the contents with `pid`
+    pub fn write(&mut self, pid: u32) -> io::Result<()> {
+        try!(self.file.set_len(0));
+        try!(self.file.seek(SeekFrom::Start(0)));
+        try!(self.file.write_all(format!("{}\n", pid).as_bytes()));
+        self.file.sync_all()
+    }
+
+    // Removes the file (while still holding the lock, so nobody else picks up a file about to
+    // disappear)
+    pub fn remove(self) {
+        fs::remove_file(&self.path).ok();
+    }
+}
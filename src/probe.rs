@@ -0,0 +1,252 @@
+//  einhyrningsins: graceful restarts for socket-based daemons
+//  Copyright (C) 2016  Bryan Newbold <bnewbold@robocracy.org>
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU General Public License as published by
+//  the Free Software Foundation, either version 3 of the License, or
+//  (at your option) any later version.
+//
+//  This program is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//  GNU General Public License for more details.
+//
+//  You should have received a copy of the GNU General Public License
+//  along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+// Active health probes. These run in their own threads (they block on the network or on a
+// command) and only report success or failure back to the main event loop.
+
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs, IpAddr, Ipv4Addr, Ipv6Addr};
+use std::process::Command;
+use time::Duration;
+
+use helper::Helpers;
+use template;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Probe {
+    // TCP connect; address defaults to the first shared socket
+    Tcp(Option<String>),
+    // HTTP GET expecting a 2xx; (address, path)
+    Http(Option<String>, String),
+    // Shell command expecting a zero exit status
+    Exec(String),
+}
+
+impl Probe {
+    // Parses specs like "tcp", "tcp:localhost:8080", "http:/health",
+    // "http:localhost:8080/health" or "exec:some command".
+    pub fn parse(spec: &str) -> Result<Probe, String> {
+        let (kind, arg) = match spec.find(':') {
+            Some(i) => (&spec[..i], Some(&spec[i + 1..])),
+            None => (spec, None),
+        };
+        match (kind, arg) {
+            ("tcp", None) | ("tcp", Some("")) => Ok(Probe::Tcp(None)),
+            ("tcp", Some(addr)) => Ok(Probe::Tcp(Some(addr.to_string()))),
+            ("http", None) | ("http", Some("")) => Ok(Probe::Http(None, "/".to_string())),
+            ("http", Some(rest)) => {
+                let rest = rest.trim_start_matches("//");
+                let (addr, path) = match rest.find('/') {
+                    Some(i) => (&rest[..i], &rest[i..]),
+                    None => (rest, "/"),
+                };
+                let addr = if addr.is_empty() {
+                    None
+                } else {
+                    Some(addr.to_string())
+                };
+                Ok(Probe::Http(addr, path.to_string()))
+            }
+            ("exec", Some(cmd)) if !cmd.is_empty() => Ok(Probe::Exec(cmd.to_string())),
+            _ => {
+                Err(format!("Invalid probe '{}'; expected tcp[:ADDR], http:[ADDR]/PATH or \
+                             exec:CMD",
+                            spec))
+            }
+        }
+    }
+
+    // Fills in per-child placeholders (like "{8000+slot}") in the probe's address
+    pub fn expand(&self, vars: &template::Vars) -> Result<Probe, String> {
+        let addr = |a: &Option<String>| match *a {
+            Some(ref a) => template::expand(a, vars).map(Some),
+            None => Ok(None),
+        };
+        Ok(match *self {
+            Probe::Tcp(ref a) => Probe::Tcp(try!(addr(a))),
+            Probe::Http(ref a, ref path) => Probe::Http(try!(addr(a)), path.clone()),
+            Probe::Exec(ref cmd) => Probe::Exec(cmd.clone()),
+        })
+    }
+
+    // Whether this probe falls back on a shared socket's address
+    pub fn needs_default_addr(&self) -> bool {
+        match *self {
+            Probe::Tcp(None) | Probe::Http(None, _) => true,
+            _ => false,
+        }
+    }
+
+    // Runs the probe once against child `pid`. `default_addr` is the first shared socket, if any.
+    pub fn check(&self,
+                 pid: u32,
+                 default_addr: Option<SocketAddr>,
+                 timeout: Duration,
+                 helpers: &Helpers)
+                 -> Result<(), String> {
+        match *self {
+            Probe::Tcp(ref addr) => {
+                let sa = try!(resolve(addr, default_addr));
+                try!(connect(sa, timeout));
+                Ok(())
+            }
+            Probe::Http(ref addr, ref path) => {
+                let sa = try!(resolve(addr, default_addr));
+                http_get(sa, path, timeout)
+            }
+            Probe::Exec(ref cmdline) => {
+                let mut cmd = Command::new("/bin/sh");
+                cmd.arg("-c").arg(cmdline).env("EINHORN_CHILD_PID", pid.to_string());
+                match try!(helpers.run(&mut cmd, timeout)) {
+                    0 => Ok(()),
+                    code => Err(format!("command exited with status {}", code)),
+                }
+            }
+        }
+    }
+}
+
+fn resolve(addr: &Option<String>, default_addr: Option<SocketAddr>) -> Result<SocketAddr, String> {
+    match *addr {
+        Some(ref a) => {
+            let mut addrs = try!(a.to_socket_addrs()
+                .map_err(|e| format!("couldn't resolve '{}': {}", a, e)));
+            addrs.next().ok_or(format!("couldn't resolve '{}'", a))
+        }
+        None => {
+            // A wildcard bind can't be connected to directly on every platform
+            match default_addr {
+                Some(SocketAddr::V4(sa)) if sa.ip().is_unspecified() => {
+                    Ok(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), sa.port()))
+                }
+                Some(SocketAddr::V6(sa)) if sa.ip().is_unspecified() => {
+                    Ok(SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)),
+                                       sa.port()))
+                }
+                Some(sa) => Ok(sa),
+                None => Err("no address to probe".to_string()),
+            }
+        }
+    }
+}
+
+fn connect(sa: SocketAddr, timeout: Duration) -> Result<TcpStream, String> {
+    let timeout = try!(timeout.to_std().map_err(|_| format!("invalid timeout {}", timeout)));
+    let stream = try!(TcpStream::connect_timeout(&sa, timeout)
+        .map_err(|e| format!("couldn't connect to {}: {}", sa, e)));
+    try!(stream.set_read_timeout(Some(timeout)).map_err(|e| e.to_string()));
+    try!(stream.set_write_timeout(Some(timeout)).map_err(|e| e.to_string()));
+    Ok(stream)
+}
+
+fn http_get(sa: SocketAddr, path: &str, timeout: Duration) -> Result<(), String> {
+    let mut stream = try!(connect(sa, timeout));
+    let req = format!("GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: einhyrningsins/{}\r\n\
+                       Connection: close\r\n\r\n",
+                      path,
+                      sa,
+                      env!("CARGO_PKG_VERSION"));
+    try!(stream.write_all(req.as_bytes()).map_err(|e| format!("write error: {}", e)));
+    let mut status_line = String::new();
+    try!(BufReader::new(stream)
+        .read_line(&mut status_line)
+        .map_err(|e| format!("read error: {}", e)));
+    // eg, "HTTP/1.1 200 OK"
+    match status_line.split(' ').nth(1) {
+        Some(code) if code.len() == 3 && code.starts_with('2') => Ok(()),
+        Some(code) => Err(format!("HTTP status {}", code)),
+        None => Err(format!("malformed HTTP response: {:?}", status_line.trim_end())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn parse_tcp() {
+        assert_eq!(Probe::parse("tcp"), Ok(Probe::Tcp(None)));
+        assert_eq!(Probe::parse("tcp:"), Ok(Probe::Tcp(None)));
+        assert_eq!(Probe::parse("tcp:localhost:8080"),
+                   Ok(Probe::Tcp(Some("localhost:8080".to_string()))));
+    }
+
+    #[test]
+    fn parse_http() {
+        assert_eq!(Probe::parse("http"), Ok(Probe::Http(None, "/".to_string())));
+        assert_eq!(Probe::parse("http:/health"), Ok(Probe::Http(None, "/health".to_string())));
+        assert_eq!(Probe::parse("http:localhost:8080/health"),
+                   Ok(Probe::Http(Some("localhost:8080".to_string()), "/health".to_string())));
+        assert_eq!(Probe::parse("http://localhost:8080"),
+                   Ok(Probe::Http(Some("localhost:8080".to_string()), "/".to_string())));
+    }
+
+    #[test]
+    fn parse_exec() {
+        assert_eq!(Probe::parse("exec:test -e /tmp/ok"),
+                   Ok(Probe::Exec("test -e /tmp/ok".to_string())));
+    }
+
+    #[test]
+    fn parse_invalid() {
+        assert!(Probe::parse("exec").is_err());
+        assert!(Probe::parse("exec:").is_err());
+        assert!(Probe::parse("udp:localhost:8125").is_err());
+        assert!(Probe::parse("").is_err());
+    }
+
+    #[test]
+    fn default_addr() {
+        assert!(Probe::parse("tcp").unwrap().needs_default_addr());
+        assert!(Probe::parse("http:/health").unwrap().needs_default_addr());
+        assert!(!Probe::parse("tcp:localhost:8080").unwrap().needs_default_addr());
+        assert!(!Probe::parse("exec:true").unwrap().needs_default_addr());
+    }
+
+    #[test]
+    fn expand_addr() {
+        let vars = template::Vars {
+            slot: 3,
+            generation: 1,
+            master_pid: 1234,
+        };
+        assert_eq!(Probe::parse("tcp:127.0.0.1:{8000+slot}").unwrap().expand(&vars),
+                   Ok(Probe::Tcp(Some("127.0.0.1:8003".to_string()))));
+        assert_eq!(Probe::parse("http:127.0.0.1:{8000+slot}/{slot}").unwrap().expand(&vars),
+                   Ok(Probe::Http(Some("127.0.0.1:8003".to_string()), "/{slot}".to_string())));
+        assert_eq!(Probe::parse("exec:echo {slot}").unwrap().expand(&vars),
+                   Ok(Probe::Exec("echo {slot}".to_string())));
+        assert!(Probe::parse("tcp:127.0.0.1:{18446744073709551615+slot}")
+            .unwrap()
+            .expand(&vars)
+            .is_err());
+    }
+
+    #[test]
+    fn resolve_wildcard() {
+        let wildcard = SocketAddr::from_str("0.0.0.0:8080").unwrap();
+        assert_eq!(resolve(&None, Some(wildcard)),
+                   Ok(SocketAddr::from_str("127.0.0.1:8080").unwrap()));
+        let wildcard = SocketAddr::from_str("[::]:8080").unwrap();
+        assert_eq!(resolve(&None, Some(wildcard)),
+                   Ok(SocketAddr::from_str("[::1]:8080").unwrap()));
+        assert!(resolve(&None, None).is_err());
+        assert!(resolve(&Some("127.0.0.1:99999".to_string()), None).is_err());
+    }
+}
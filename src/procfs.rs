@@ -0,0 +1,108 @@
+//  einhyrningsins: graceful restarts for socket-based daemons
+//  Copyright (C) 2016  Bryan Newbold <bnewbold@robocracy.org>
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU General Public License as published by
+//  the Free Software Foundation, either version 3 of the License, or
+//  (at your option) any later version.
+//
+//  This program is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//  GNU General Public License for more details.
+//
+//  You should have received a copy of the GNU General Public License
+//  along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+// Minimal readers for the Linux /proc filesystem. See proc(5).
+
+use std::io::prelude::*;
+use std::fs;
+use std::fs::File;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use libc;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ProcStat {
+    // Exited, but not yet reaped by its parent
+    pub zombie: bool,
+    pub ppid: u32,
+    // Resident set size, in bytes
+    pub rss: u64,
+    // User plus system CPU time, in seconds
+    pub cpu_secs: u64,
+    // When the process started, in clock ticks since boot; together with the pid, this
+    // identifies a process even if pids get reused
+    pub starttime: u64,
+}
+
+pub fn stat(pid: u32) -> Result<ProcStat, String> {
+    let path = format!("/proc/{}/stat", pid);
+    let mut raw = String::new();
+    try!(File::open(&path)
+        .and_then(|mut f| f.read_to_string(&mut raw))
+        .map_err(|e| format!("couldn't read {}: {}", path, e)));
+
+    // The command name (field 2) is in parens and may itself contain spaces or parens
+    let after_comm = match raw.rfind(')') {
+        Some(i) => &raw[i + 1..],
+        None => return Err(format!("malformed {}", path)),
+    };
+    // Counting from field 3 (state)
+    let fields: Vec<&str> = after_comm.split_whitespace().collect();
+    let field = |n: usize| -> Result<u64, String> {
+        fields.get(n - 3)
+            .and_then(|f| u64::from_str(f).ok())
+            .ok_or(format!("malformed {} (field {})", path, n))
+    };
+
+    let ticks = unsafe { libc::sysconf(libc::_SC_CLK_TCK) } as u64;
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+    Ok(ProcStat {
+        zombie: fields.get(0) == Some(&"Z"),
+        ppid: try!(field(4)) as u32,
+        rss: try!(field(24)) * page_size,
+        cpu_secs: (try!(field(14)) + try!(field(15))) / ticks,
+        starttime: try!(field(22)),
+    })
+}
+
+// The processes whose parent is `pid`
+pub fn children(pid: u32) -> Vec<u32> {
+    let entries = match fs::read_dir("/proc") {
+        Ok(entries) => entries,
+        Err(_) => return vec![],
+    };
+    entries.filter_map(|e| e.ok())
+        .filter_map(|e| e.file_name().to_str().and_then(|name| u32::from_str(name).ok()))
+        .filter(|&p| stat(p).map(|s| s.ppid == pid).unwrap_or(false))
+        .collect()
+}
+
+// The paths of unix sockets that are listening (in our network namespace), as they were bound
+pub fn unix_listeners() -> Result<Vec<PathBuf>, String> {
+    let path = "/proc/net/unix";
+    let mut raw = String::new();
+    try!(File::open(path)
+        .and_then(|mut f| f.read_to_string(&mut raw))
+        .map_err(|e| format!("couldn't read {}: {}", path, e)));
+
+    // After a header: Num, RefCount, Protocol, Flags, Type, St, Inode and (if bound) Path
+    Ok(raw.lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let flags = fields.get(3).and_then(|f| u32::from_str_radix(f, 16).ok());
+            match flags {
+                // __SO_ACCEPTCON, set by listen()
+                Some(flags) if flags & 0x10000 != 0 && fields.len() > 7 => {
+                    Some(PathBuf::from(fields[7..].join(" ")))
+                }
+                _ => None,
+            }
+        })
+        .collect())
+}
@@ -0,0 +1,130 @@
+//  einhyrningsins: graceful restarts for socket-based daemons
+//  Copyright (C) 2016  Bryan Newbold <bnewbold@robocracy.org>
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU General Public License as published by
+//  the Free Software Foundation, either version 3 of the License, or
+//  (at your option) any later version.
+//
+//  This program is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//  GNU General Public License for more details.
+//
+//  You should have received a copy of the GNU General Public License
+//  along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+// Per-child placeholders in program arguments and environment values, like "{slot}" or
+// "{8000+slot}". Anything in braces that isn't a known placeholder is passed through untouched,
+// so existing arguments containing braces (eg, JSON) keep working.
+
+use std::str::FromStr;
+
+pub struct Vars {
+    pub slot: usize,
+    pub generation: u64,
+    pub master_pid: u32,
+}
+
+impl Vars {
+    // None if `name` isn't a placeholder at all
+    fn lookup(&self, name: &str) -> Result<Option<String>, String> {
+        match name.trim() {
+            "slot" => Ok(Some(self.slot.to_string())),
+            "generation" => Ok(Some(self.generation.to_string())),
+            "pid_of_master" => Ok(Some(self.master_pid.to_string())),
+            other => {
+                // Offsets, like "8000+slot" (or "slot+8000")
+                let mut parts = other.splitn(2, '+').map(|p| p.trim());
+                let (a, b) = match (parts.next(), parts.next()) {
+                    (Some(a), Some(b)) => (a, b),
+                    _ => return Ok(None),
+                };
+                let (offset, value) = match (a, b) {
+                    ("slot", n) | (n, "slot") => (n, self.slot as u64),
+                    ("generation", n) | (n, "generation") => (n, self.generation),
+                    _ => return Ok(None),
+                };
+                match u64::from_str(offset) {
+                    Ok(n) => {
+                        match n.checked_add(value) {
+                            Some(sum) => Ok(Some(sum.to_string())),
+                            None => Err(format!("Placeholder '{{{}}}' is out of range", name)),
+                        }
+                    }
+                    Err(_) => Ok(None),
+                }
+            }
+        }
+    }
+}
+
+pub fn expand(s: &str, vars: &Vars) -> Result<String, String> {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let found = match after.find('}') {
+            Some(end) => try!(vars.lookup(&after[..end])).map(|v| (end, v)),
+            None => None,
+        };
+        match found {
+            Some((end, value)) => {
+                out.push_str(&value);
+                rest = &after[end + 1..];
+            }
+            None => {
+                out.push('{');
+                rest = after;
+            }
+        }
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars() -> Vars {
+        Vars {
+            slot: 2,
+            generation: 5,
+            master_pid: 1234,
+        }
+    }
+
+    #[test]
+    fn placeholders() {
+        assert_eq!(expand("--slot={slot}", &vars()).unwrap(), "--slot=2");
+        assert_eq!(expand("{generation}/{pid_of_master}", &vars()).unwrap(), "5/1234");
+        assert_eq!(expand("{ slot }", &vars()).unwrap(), "2");
+    }
+
+    #[test]
+    fn offsets() {
+        assert_eq!(expand("{8000+slot}", &vars()).unwrap(), "8002");
+        assert_eq!(expand("{slot+8000}", &vars()).unwrap(), "8002");
+        assert_eq!(expand("{100 + generation}", &vars()).unwrap(), "105");
+    }
+
+    #[test]
+    fn passes_through_other_braces() {
+        assert_eq!(expand("{\"a\": 1}", &vars()).unwrap(), "{\"a\": 1}");
+        assert_eq!(expand("{slot", &vars()).unwrap(), "{slot");
+        assert_eq!(expand("{{slot}}", &vars()).unwrap(), "{2}");
+        assert_eq!(expand("{x+slot}", &vars()).unwrap(), "{x+slot}");
+        assert_eq!(expand("{8000+pid}", &vars()).unwrap(), "{8000+pid}");
+    }
+
+    #[test]
+    fn offset_overflow() {
+        assert_eq!(expand("{18446744073709551613+slot}", &vars()).unwrap(),
+                   "18446744073709551615");
+        assert!(expand("{18446744073709551614+slot}", &vars()).is_err());
+        assert!(expand("{generation+18446744073709551615}", &vars()).is_err());
+    }
+}
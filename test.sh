@@ -33,4 +33,77 @@ $EINCTL -e die > /dev/null
 sleep 1
 jobs > /dev/null
 test -z "`jobs`"
+
+echo "### Each shutdown step reaches the children"
+rm -f $EINSOCK
+$EIN --shutdown-signals QUIT:30,TERM:30,KILL -n 2 -- sleep 300 > /dev/null &
+sleep 0.5
+$EINCTL -e die > /dev/null
+sleep 1
+test -z "`jobs -r`"
+rm -f $EINSOCK
+$EIN --shutdown-signals USR2:30,TERM:30,KILL -n 2 -- sleep 300 > /dev/null &
+sleep 0.5
+kill -TERM %%
+sleep 1
+test -z "`jobs -r`"
+
+echo "### Children die with the master (--pdeathsig)"
+rm -f $EINSOCK
+$EIN --pdeathsig TERM -- sleep 300 > /dev/null &
+MASTER=$!
+sleep 0.5
+CHILD=`pgrep -P $MASTER sleep`
+kill -KILL $MASTER
+wait $MASTER || true
+sleep 0.5
+! kill -0 $CHILD 2> /dev/null
+echo "### Reloading a program that can't be run keeps the children"
+rm -f $EINSOCK
+EINCFG="/tmp/einhyrningsins_tests.json"
+echo '{"program": "sleep", "args": ["300"]}' > $EINCFG
+$EIN --config $EINCFG > /dev/null &
+MASTER=$!
+sleep 0.5
+CHILD=`pgrep -P $MASTER sleep`
+echo '{"program": "/nonexistent/sleep", "args": ["300"]}' > $EINCFG
+$EINCTL -e reload | grep -q "not reloaded"
+kill -HUP $MASTER
+sleep 0.5
+kill -0 $MASTER
+test "`pgrep -P $MASTER sleep`" = "$CHILD"
+$EINCTL -e die > /dev/null
+sleep 1
+rm -f $EINCFG
+test -z "`jobs -r`"
+echo "### Control commands are answered while pre-upgrade hooks run"
+rm -f $EINSOCK
+$EIN --hook "pre-upgrade:sleep 2; true" -- sleep 300 > /dev/null &
+MASTER=$!
+sleep 0.5
+CHILD=`pgrep -P $MASTER -x sleep`
+$EINCTL -e upgrade > /dev/null
+timeout 1 $EINCTL -e status > /dev/null
+test "`pgrep -P $MASTER -x sleep`" = "$CHILD"
+sleep 3
+test "`pgrep -P $MASTER -x sleep`" != "$CHILD"
+$EINCTL -e die > /dev/null
+sleep 1
+test -z "`jobs -r`"
+echo "### A reload that drops a unix socket removes its file"
+rm -f $EINSOCK
+EINCFG="/tmp/einhyrningsins_tests.json"
+APPSOCK="/tmp/einhyrningsins_tests_app.sock"
+echo "{\"program\": \"sleep\", \"args\": [\"300\"], \"bind\": [\"unix:$APPSOCK\"]}" > $EINCFG
+$EIN --config $EINCFG > /dev/null &
+sleep 0.5
+test -S $APPSOCK
+echo '{"program": "sleep", "args": ["300"]}' > $EINCFG
+$EINCTL -e reload > /dev/null
+sleep 4
+test ! -e $APPSOCK
+$EINCTL -e die > /dev/null
+sleep 1
+rm -f $EINCFG
+test -z "`jobs -r`"
 trap "trap - SIGTERM" SIGINT SIGTERM EXIT